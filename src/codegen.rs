@@ -1,19 +1,37 @@
-use super::parse::{ Node, NodeKind, Program, Function };
+use super::parse::{ Node, NodeKind, Program, Function, VarRef };
+use super::diagnostic::{ Diagnostic, Error };
+use super::intern::Interner;
+use super::types::{ Type, TypeKind, get_common_type, is_integer, size_of, align_of };
 static mut CUR: i64 = 0;
 static mut LABELSEQ: usize = 1;
 
+struct GenContext<'a> {
+    prog: &'a Program,
+    names: &'a Interner,
+    f: &'a Function,        // Function being generated
+    out: &'a mut String,    // Assembly output
+}
+
+// Append a line of assembly to the output
+macro_rules! emit {
+    ($out:expr, $($arg:tt)*) => {{
+        $out.push_str(&format!($($arg)*));
+        $out.push('\n');
+    }};
+}
+
 // get_cur(1) => CUR++ (C like)
 // get_cur(-1) => CUR-- (C like)
-fn get_cur(n: i64) -> usize {
+fn get_cur(n: i64) -> Result<usize, Diagnostic> {
     let t;
     unsafe {
         t = CUR;
         if CUR + n < 0 {
-            panic!("CUR is less than zero: {}", CUR+n);
+            return Err(Diagnostic::error(format!("internal error: register stack underflow: {}", CUR + n)));
         }
         CUR += n
     }
-    t as usize
+    Ok(t as usize)
 }
 
 fn get_labelseq() -> usize {
@@ -24,242 +42,513 @@ fn get_labelseq() -> usize {
     }
 }
 
-fn argreg(idx: usize) -> String {
-    let argreg = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
-    argreg[idx].to_string()
+fn argreg(idx: usize) -> Result<String, Diagnostic> {
+    argreg_sized(idx, 8)
 }
 
-fn reg(idx: usize) -> String {
+// The lower `size` bytes of an argument register
+fn argreg_sized(idx: usize, size: usize) -> Result<String, Diagnostic> {
+    let argreg = match size {
+        1 => ["dil", "sil", "dl", "cl", "r8b", "r9b"],
+        2 => ["di", "si", "dx", "cx", "r8w", "r9w"],
+        4 => ["edi", "esi", "edx", "ecx", "r8d", "r9d"],
+        _ => ["rdi", "rsi", "rdx", "rcx", "r8", "r9"],
+    };
+    if argreg.len() <= idx {
+        return Err(Diagnostic::error("too many arguments")
+            .with_note(format!("toycc can pass at most {} arguments in registers", argreg.len())));
+    }
+
+    Ok(argreg[idx].to_string())
+}
+
+fn reg(idx: usize) -> Result<String, Diagnostic> {
     let r = ["r10", "r11", "r12", "r13", "r14", "r15"];
     if r.len() <= idx {
-        panic!("register out of range: {}", idx);
+        return Err(Diagnostic::error("expression is too complex")
+            .with_note(format!("toycc can hold at most {} temporary values", r.len())));
     }
 
-    r[idx].to_string()
+    Ok(r[idx].to_string())
 }
 
-fn gen_addr(node: Node, f: &Function) {
+// Allocate a register for the value of node
+fn push_reg(node: &Node) -> Result<String, Diagnostic> {
+    let cur = get_cur(1)?;
+    reg(cur).map_err(|diag| match &node.span {
+        Some(span) if diag.primary.is_none() => diag.with_primary(span, ""),
+        _ => diag,
+    })
+}
+
+// Identifiers may contain non-ASCII characters. The assembler accepts
+// any symbol name in double quotes.
+fn symbol(name: &str) -> String {
+    if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name)
+    }
+}
+
+fn gen_addr(node: Node, gc: &mut GenContext) -> Result<(), Diagnostic> {
     match node.kind {
         NodeKind::Var => {
-            println!("  lea {}, [rbp-{}]", reg(get_cur(1)), f.locals[node.var.unwrap()].offset);
+            let r = push_reg(&node)?;
+            match node.var.unwrap() {
+                VarRef::Local(i) => emit!(gc.out, "  lea {}, [rbp-{}]", r, gc.f.locals[i].offset),
+                VarRef::Global(i) => emit!(gc.out, "  lea {}, [rip+{}]", r, symbol(gc.names.get(gc.prog.globals[i].name))),
+            }
         }
         NodeKind::Deref => {
-            gen_expr(*node.lhs.unwrap(), f);
+            gen_expr(*node.lhs.unwrap(), gc)?;
         }
         _ => {
-            println!("{:#?}", node);
-            panic!("not an lvalue");
+            let mut diag = Diagnostic::error("not an lvalue");
+            if let Some(span) = &node.span {
+                diag = diag.with_primary(span, "this expression has no address");
+            }
+            return Err(diag);
         }
     }
+    Ok(())
+}
+
+// Load a value from the address on the top of the register stack.
+// An array is not loaded: its value is the address of the first element.
+// Values narrower than 8 bytes are sign- or zero-extended to 64 bits.
+fn load(node: &Node, gc: &mut GenContext) -> Result<(), Diagnostic> {
+    if node.ty.kind == TypeKind::Array {
+        return Ok(());
+    }
+    let r = reg(get_cur(0)?-1)?;
+    let ext = if node.ty.is_unsigned { "movzx" } else { "movsx" };
+    match size_of(&node.ty) {
+        1 => emit!(gc.out, "  {} {}, BYTE PTR [{}]", ext, r, r),
+        2 => emit!(gc.out, "  {} {}, WORD PTR [{}]", ext, r, r),
+        4 if node.ty.is_unsigned => emit!(gc.out, "  mov {}d, DWORD PTR [{}]", r, r),
+        4 => emit!(gc.out, "  movsxd {}, DWORD PTR [{}]", r, r),
+        _ => emit!(gc.out, "  mov {}, [{}]", r, r),
+    }
+    Ok(())
 }
 
-fn load() {
-    let cur = get_cur(0)-1;
-    println!("  mov {}, [{}]", reg(cur), reg(cur));
+// Store the lower bytes of a value as a value of type ty.
+fn store(ty: &Type, gc: &mut GenContext) -> Result<(), Diagnostic> {
+    let cur = get_cur(-1)?;
+    let (addr, r) = (reg(cur-1)?, reg(cur-2)?);
+    match size_of(ty) {
+        1 => emit!(gc.out, "  mov [{}], {}b", addr, r),
+        2 => emit!(gc.out, "  mov [{}], {}w", addr, r),
+        4 => emit!(gc.out, "  mov [{}], {}d", addr, r),
+        _ => emit!(gc.out, "  mov [{}], {}", addr, r),
+    }
+    Ok(())
 }
 
-fn store() {
-    let cur = get_cur(-1);
-    println!("  mov [{}], {}", reg(cur-1), reg(cur-2));
+// Sign- or zero-extend the lower bytes of a register so that it holds
+// a value of type ty. Integers narrower than 8 bytes are always kept
+// extended to 64 bits in registers.
+fn extend(ty: &Type, r: &str, gc: &mut GenContext) {
+    if !is_integer(ty) {
+        return;
+    }
+    let ext = if ty.is_unsigned { "movzx" } else { "movsx" };
+    match size_of(ty) {
+        1 => emit!(gc.out, "  {} {}, {}b", ext, r, r),
+        2 => emit!(gc.out, "  {} {}, {}w", ext, r, r),
+        4 if ty.is_unsigned => emit!(gc.out, "  mov {}d, {}d", r, r),
+        4 => emit!(gc.out, "  movsxd {}, {}d", r, r),
+        _ => {}
+    }
 }
 
-fn gen_expr(node: Node, f: &Function) {
+fn gen_expr(mut node: Node, gc: &mut GenContext) -> Result<(), Diagnostic> {
     match node.kind {
         NodeKind::Num => {
-            println!("  mov {}, {}", reg(get_cur(1)), node.val);
-            return;
+            emit!(gc.out, "  mov {}, {}", push_reg(&node)?, node.val);
+            return Ok(());
         }
         NodeKind::Var => {
-            gen_addr(node, f);
-            load();
-            return;
+            gen_addr(node.clone(), gc)?;
+            load(&node, gc)?;
+            return Ok(());
         }
         NodeKind::Assign => {
-            gen_expr(*node.rhs.unwrap(), f);
-            gen_addr(*node.lhs.unwrap(), f);
-            store();
-            return;
+            gen_expr(*node.rhs.take().unwrap(), gc)?;
+            gen_addr(*node.lhs.take().unwrap(), gc)?;
+            store(&node.ty, gc)?;
+            // The value of an assignment is the value stored.
+            extend(&node.ty, &reg(get_cur(0)?-1)?, gc);
+            return Ok(());
+        }
+        NodeKind::Comma => {
+            gen_expr(*node.lhs.unwrap(), gc)?;
+            get_cur(-1)?;
+            gen_expr(*node.rhs.unwrap(), gc)?;
+            return Ok(());
+        }
+        NodeKind::Cond => {
+            let seq = get_labelseq();
+            gen_expr(*node.cond.unwrap(), gc)?;
+            let cur = get_cur(-1)?;
+            emit!(gc.out, "  cmp {}, 0", reg(cur-1)?);
+            emit!(gc.out, "  je .L.else.{}", seq);
+            gen_expr(*node.then.unwrap(), gc)?;
+            get_cur(-1)?;
+            emit!(gc.out, "  jmp .L.end.{}", seq);
+            emit!(gc.out, ".L.else.{}:", seq);
+            gen_expr(*node.els.take().unwrap(), gc)?;
+            emit!(gc.out, ".L.end.{}:", seq);
+            extend(&node.ty, &reg(get_cur(0)?-1)?, gc);
+            return Ok(());
         }
         NodeKind::Deref => {
-            gen_expr(*node.lhs.unwrap(), f);
-            load();
-            return;
+            gen_expr(*node.lhs.take().unwrap(), gc)?;
+            load(&node, gc)?;
+            return Ok(());
         }
         NodeKind::Addr => {
-            gen_addr(*node.lhs.unwrap(), f);
-            return;
+            gen_addr(*node.lhs.unwrap(), gc)?;
+            return Ok(());
+        }
+        NodeKind::Not => {
+            gen_expr(*node.lhs.unwrap(), gc)?;
+            let r = reg(get_cur(0)?-1)?;
+            emit!(gc.out, "  cmp {}, 0", r);
+            emit!(gc.out, "  sete al");
+            emit!(gc.out, "  movzb {}, al", r);
+            return Ok(());
+        }
+        NodeKind::BitNot => {
+            gen_expr(*node.lhs.take().unwrap(), gc)?;
+            let r = reg(get_cur(0)?-1)?;
+            emit!(gc.out, "  not {}", r);
+            extend(&node.ty, &r, gc);
+            return Ok(());
+        }
+        NodeKind::LogAnd | NodeKind::LogOr => {
+            // The right operand is skipped once the result is known.
+            let seq = get_labelseq();
+            let (jump, skipped, done) = if node.kind == NodeKind::LogAnd {
+                ("je", 0, 1)
+            } else {
+                ("jne", 1, 0)
+            };
+
+            gen_expr(*node.lhs.take().unwrap(), gc)?;
+            let cur = get_cur(-1)?;
+            emit!(gc.out, "  cmp {}, 0", reg(cur-1)?);
+            emit!(gc.out, "  {} .L.skip.{}", jump, seq);
+
+            gen_expr(*node.rhs.take().unwrap(), gc)?;
+            let cur = get_cur(-1)?;
+            emit!(gc.out, "  cmp {}, 0", reg(cur-1)?);
+            emit!(gc.out, "  {} .L.skip.{}", jump, seq);
+
+            let r = push_reg(&node)?;
+            emit!(gc.out, "  mov {}, {}", r, done);
+            emit!(gc.out, "  jmp .L.end.{}", seq);
+            emit!(gc.out, ".L.skip.{}:", seq);
+            emit!(gc.out, "  mov {}, {}", r, skipped);
+            emit!(gc.out, ".L.end.{}:", seq);
+            return Ok(());
         }
         NodeKind::Funcall => {
-            let mut nargs = 0;
-            for arg in node.args.unwrap() {
-                gen_expr(*arg, f);
-                nargs += 1;
+            let args = node.args.clone().unwrap();
+            let nargs = args.len();
+            if let Err(diag) = argreg(nargs.saturating_sub(1)) {
+                let diag = match &args[nargs-1].span {
+                    Some(span) => diag.with_primary(span, "this argument does not fit in a register"),
+                    None => diag,
+                };
+                return Err(diag);
+            }
+            for arg in args {
+                gen_expr(*arg, gc)?;
             }
 
             for i in 1..nargs+1 {
-                let cur = get_cur(-1);
-                println!("  mov {}, {}", argreg(nargs-i), reg(cur-1));
+                let cur = get_cur(-1)?;
+                emit!(gc.out, "  mov {}, {}", argreg(nargs-i)?, reg(cur-1)?);
             }
 
 
-            println!("  push r10");
-            println!("  push r11");
-//            println!("  mov rax, 0");
-            println!("  call {}", node.funcname);
-            println!("  pop r11");
-            println!("  pop r10");
-            println!("  mov {}, rax", reg(get_cur(1)));
-            return;
+            emit!(gc.out, "  push r10");
+            emit!(gc.out, "  push r11");
+//            emit!(gc.out, "  mov rax, 0");
+            emit!(gc.out, "  call {}", symbol(gc.names.get(node.funcname)));
+            emit!(gc.out, "  pop r11");
+            emit!(gc.out, "  pop r10");
+            // The callee may leave garbage in the upper bits of rax.
+            let r = push_reg(&node)?;
+            emit!(gc.out, "  mov {}, rax", r);
+            extend(&node.ty, &r, gc);
+            return Ok(());
         }
         _ => {}
     }
 
-    gen_expr(*node.lhs.unwrap(), f);
-    gen_expr(*node.rhs.unwrap(), f);
+    let lhs = *node.lhs.unwrap();
+    let rhs = *node.rhs.unwrap();
+    let common = get_common_type(&lhs.ty, &rhs.ty);
+    let unsigned = match node.kind {
+        NodeKind::Shl | NodeKind::Shr => node.ty.is_unsigned,
+        _ => common.is_unsigned,
+    };
+    let is_shift = matches!(node.kind, NodeKind::Shl | NodeKind::Shr);
+    let convert = |ty: &Type| !is_shift && (ty.kind != common.kind || ty.is_unsigned != common.is_unsigned);
+    let (convert_lhs, convert_rhs) = (convert(&lhs.ty), convert(&rhs.ty));
+    gen_expr(lhs, gc)?;
+    gen_expr(rhs, gc)?;
 
-    let rd;
-    let rs;
-    let cur = get_cur(-1);
-    rd = reg(cur-2);
-    rs = reg(cur-1);
+    let cur = get_cur(-1)?;
+    let rd = reg(cur-2)?;
+    let rs = reg(cur-1)?;
+
+    // Convert both operands to the common type, e.g. -1 to 0xffffffff
+    // when compared with an unsigned int.
+    if convert_lhs {
+        extend(&common, &rd, gc);
+    }
+    if convert_rhs {
+        extend(&common, &rs, gc);
+    }
 
     match node.kind {
         NodeKind::Add => {
-            println!("  add {}, {}", rd, rs);
+            emit!(gc.out, "  add {}, {}", rd, rs);
         }
         NodeKind::Sub => {
-            println!("  sub {}, {}", rd, rs);
+            emit!(gc.out, "  sub {}, {}", rd, rs);
         }
         NodeKind::Mul => {
-            println!("  imul {}, {}", rd, rs);
+            emit!(gc.out, "  imul {}, {}", rd, rs);
+        }
+        NodeKind::Div | NodeKind::Mod => {
+            emit!(gc.out, "  mov rax, {}", rd);
+            if unsigned {
+                emit!(gc.out, "  xor edx, edx");
+                emit!(gc.out, "  div {}", rs);
+            } else {
+                emit!(gc.out, "  cqo");
+                emit!(gc.out, "  idiv {}", rs);
+            }
+            if node.kind == NodeKind::Div {
+                emit!(gc.out, "  mov {}, rax", rd);
+            } else {
+                emit!(gc.out, "  mov {}, rdx", rd);
+            }
         }
-        NodeKind::Div => {
-            println!("  mov rax, {}", rd);
-            println!("  cqo");
-            println!("  idiv {}", rs);
-            println!("  mov {}, rax", rd);
+        NodeKind::Shl => {
+            emit!(gc.out, "  mov rcx, {}", rs);
+            emit!(gc.out, "  shl {}, cl", rd);
+        }
+        NodeKind::Shr => {
+            emit!(gc.out, "  mov rcx, {}", rs);
+            emit!(gc.out, "  {} {}, cl", if unsigned { "shr" } else { "sar" }, rd);
+        }
+        NodeKind::BitAnd => {
+            emit!(gc.out, "  and {}, {}", rd, rs);
+        }
+        NodeKind::BitOr => {
+            emit!(gc.out, "  or {}, {}", rd, rs);
+        }
+        NodeKind::BitXor => {
+            emit!(gc.out, "  xor {}, {}", rd, rs);
         }
         NodeKind::Equal => {
-            println!("  cmp {}, {}", rd, rs);
-            println!("  sete al");
-            println!("  movzb {}, al", rd);
+            emit!(gc.out, "  cmp {}, {}", rd, rs);
+            emit!(gc.out, "  sete al");
+            emit!(gc.out, "  movzb {}, al", rd);
         }
         NodeKind::Ne => {
-            println!("  cmp {}, {}", rd, rs);
-            println!("  setne al");
-            println!("  movzb {}, al", rd);
+            emit!(gc.out, "  cmp {}, {}", rd, rs);
+            emit!(gc.out, "  setne al");
+            emit!(gc.out, "  movzb {}, al", rd);
         }
         NodeKind::Lt => {
-            println!("  cmp {}, {}", rd, rs);
-            println!("  setl al");
-            println!("  movzb {}, al", rd);
+            emit!(gc.out, "  cmp {}, {}", rd, rs);
+            emit!(gc.out, "  {} al", if unsigned { "setb" } else { "setl" });
+            emit!(gc.out, "  movzb {}, al", rd);
         }
         NodeKind::Le => {
-            println!("  cmp {}, {}", rd, rs);
-            println!("  setle al");
-            println!("  movzb {}, al", rd);
+            emit!(gc.out, "  cmp {}, {}", rd, rs);
+            emit!(gc.out, "  {} al", if unsigned { "setbe" } else { "setle" });
+            emit!(gc.out, "  movzb {}, al", rd);
         }
-        _ => panic!("invalid expression")
+        _ => return Err(Diagnostic::error(format!("internal error: invalid expression: {:?}", node.kind)))
     }
+
+    // Wrap around to the width of the result type.
+    if !matches!(node.kind, NodeKind::Equal | NodeKind::Ne | NodeKind::Lt | NodeKind::Le) {
+        extend(&node.ty, &rd, gc);
+    }
+    Ok(())
 }
 
-fn gen_stmt(node: Node, f: &Function) {
+fn gen_stmt(node: Node, gc: &mut GenContext) -> Result<(), Diagnostic> {
     match node.kind {
         NodeKind::Return => {
-            gen_expr(*node.lhs.unwrap(), f);
-            let cur = get_cur(-1);
-            println!("  mov rax, {}", reg(cur-1));
-            println!("  jmp .L.return.{}", f.name);
+            gen_expr(*node.lhs.unwrap(), gc)?;
+            let cur = get_cur(-1)?;
+            emit!(gc.out, "  mov rax, {}", reg(cur-1)?);
+            emit!(gc.out, "  jmp {}", symbol(&format!(".L.return.{}", gc.names.get(gc.f.name))));
         }
         NodeKind::ExprStmt => {
-            gen_expr(*node.lhs.unwrap(), f);
+            gen_expr(*node.lhs.unwrap(), gc)?;
             unsafe {
                 CUR -= 1;
             }
         }
         NodeKind::If => {
-            let seq = get_labelseq();            
-            if let Some(_) = node.els {
-                gen_expr(*node.cond.unwrap(), f);
-                let cur = get_cur(-1);
-                println!("  cmp {}, 0", reg(cur-1));
-                println!("  je .L.else.{}", seq);
-                gen_stmt(*node.then.unwrap(), f);
-                println!("  jmp .L.end.{}", seq);
-                println!(".L.else.{}:", seq);
-                gen_stmt(*node.els.unwrap(), f);
-                println!(".L.end.{}:", seq);
+            let seq = get_labelseq();
+            if let Some(els) = node.els {
+                gen_expr(*node.cond.unwrap(), gc)?;
+                let cur = get_cur(-1)?;
+                emit!(gc.out, "  cmp {}, 0", reg(cur-1)?);
+                emit!(gc.out, "  je .L.else.{}", seq);
+                gen_stmt(*node.then.unwrap(), gc)?;
+                emit!(gc.out, "  jmp .L.end.{}", seq);
+                emit!(gc.out, ".L.else.{}:", seq);
+                gen_stmt(*els, gc)?;
+                emit!(gc.out, ".L.end.{}:", seq);
             } else {
-                gen_expr(*node.cond.unwrap(), f);
-                let cur = get_cur(-1);
-                println!("  cmp {}, 0", reg(cur-1));
-                println!("  je .L.end.{}", seq);
-                gen_stmt(*node.then.unwrap(), f);
-                println!(".L.end.{}:", seq);
+                gen_expr(*node.cond.unwrap(), gc)?;
+                let cur = get_cur(-1)?;
+                emit!(gc.out, "  cmp {}, 0", reg(cur-1)?);
+                emit!(gc.out, "  je .L.end.{}", seq);
+                gen_stmt(*node.then.unwrap(), gc)?;
+                emit!(gc.out, ".L.end.{}:", seq);
             }
         }
         NodeKind::For => {
             let seq = get_labelseq();
-            if let Some(_) = node.init {
-                gen_stmt(*node.init.unwrap(), f);
+            if let Some(init) = node.init {
+                gen_stmt(*init, gc)?;
             }
-            println!(".L.begin.{}:", seq);
-            if let Some(_) = node.cond {
-                gen_expr(*node.cond.unwrap(), f);
-                let cur = get_cur(-1);
-                println!("  cmp {}, 0", reg(cur-1));
-                println!("  je .L.end.{}", seq);
+            emit!(gc.out, ".L.begin.{}:", seq);
+            if let Some(cond) = node.cond {
+                gen_expr(*cond, gc)?;
+                let cur = get_cur(-1)?;
+                emit!(gc.out, "  cmp {}, 0", reg(cur-1)?);
+                emit!(gc.out, "  je .L.end.{}", seq);
             }
-            gen_stmt(*node.then.unwrap(), f);
-            if let Some(_) = node.inc {
-                gen_stmt(*node.inc.unwrap(), f);
+            gen_stmt(*node.then.unwrap(), gc)?;
+            if let Some(inc) = node.inc {
+                gen_stmt(*inc, gc)?;
             }
-            println!("  jmp .L.begin.{}", seq);
-            println!(".L.end.{}:", seq);
+            emit!(gc.out, "  jmp .L.begin.{}", seq);
+            emit!(gc.out, ".L.end.{}:", seq);
         }
         NodeKind::Block => {
             for n in node.body.unwrap() {
-                gen_stmt(*n, f);
+                gen_stmt(*n, gc)?;
             }
         }
-        _ => panic!("invalid statement")
+        _ => return Err(Diagnostic::error(format!("internal error: invalid statement: {:?}", node.kind)))
     }
+    Ok(())
 }
 
-pub fn codegen(prog: Program) {
-    println!(".intel_syntax noprefix");
+fn emit_data(prog: &Program, names: &Interner, out: &mut String) {
+    for var in &prog.globals {
+        // Functions are emitted in .text.
+        if var.ty.kind == TypeKind::Func {
+            continue;
+        }
+        let name = symbol(names.get(var.name));
+
+        // String literals are local to the file and read-only.
+        if var.is_readonly {
+            let data = var.init_data.as_deref().unwrap_or_default();
+            let bytes: Vec<String> = data.iter().map(|b| b.to_string()).collect();
+            emit!(out, ".section .rodata");
+            emit!(out, "{}:", name);
+            emit!(out, "  .byte {}", bytes.join(", "));
+            continue;
+        }
+
+        let size = size_of(&var.ty);
+        emit!(out, "{}", if var.init_data.is_some() { ".data" } else { ".bss" });
+        emit!(out, ".globl {}", name);
+        emit!(out, ".type {}, @object", name);
+        emit!(out, ".size {}, {}", name, size);
+        emit!(out, ".align {}", align_of(&var.ty));
+        emit!(out, "{}:", name);
+
+        let data = match &var.init_data {
+            Some(data) => data,
+            None => {
+                emit!(out, "  .zero {}", size);
+                continue;
+            }
+        };
+
+        // Initial value, with addresses of other globals filled in by the linker
+        let mut pos = 0;
+        while pos < data.len() {
+            match var.rel.iter().find(|rel| rel.offset == pos) {
+                Some(rel) => {
+                    emit!(out, "  .quad {}{:+}", symbol(names.get(rel.label)), rel.addend);
+                    pos += 8;
+                }
+                None => {
+                    emit!(out, "  .byte {}", data[pos]);
+                    pos += 1;
+                }
+            }
+        }
+    }
+}
+
+pub fn codegen(prog: Program, names: &Interner) -> Result<String, Error> {
+    let mut out = String::new();
+    let out = &mut out;
+    unsafe {
+        CUR = 0;
+    }
+
+    emit!(out, ".intel_syntax noprefix");
+    emit_data(&prog, names, out);
+
+    emit!(out, ".text");
     for f in &prog.functions {
-        println!(".globl {}", f.name);
-        println!("{}:", f.name);
+        emit!(out, ".globl {}", symbol(names.get(f.name)));
+        emit!(out, "{}:", symbol(names.get(f.name)));
 
         // Prologue. r12-r15 are callee-saved registers.
-        println!("  push rbp");
-        println!("  mov rbp, rsp");
-        println!("  sub rsp, {}", f.stack_size);
-        println!("  mov [rsp-8], r12");
-        println!("  mov [rsp-16], r13");
-        println!("  mov [rsp-24], r14");
-        println!("  mov [rsp-32], r15");
+        emit!(out, "  push rbp");
+        emit!(out, "  mov rbp, rsp");
+        emit!(out, "  sub rsp, {}", f.stack_size);
+        emit!(out, "  mov [rsp-8], r12");
+        emit!(out, "  mov [rsp-16], r13");
+        emit!(out, "  mov [rsp-24], r14");
+        emit!(out, "  mov [rsp-32], r15");
 
         // Save arguments to the stack
-        let mut i = f.params.len();
-
-        for j in 0..f.params.len() {
-            i -= 1;
-            println!("  mov [rbp-{}], {}", f.locals[j].offset, argreg(i))
+        for i in 0..f.params.len() {
+            let var = &f.locals[i];
+            let r = argreg_sized(i, size_of(&var.ty)).map_err(|diag| match &var.span {
+                Some(span) => diag.with_primary(span, "this parameter does not fit in a register"),
+                None => diag,
+            })?;
+            emit!(out, "  mov [rbp-{}], {}", var.offset, r);
         }
 
         // Emit code
-        gen_stmt(f.node.clone(), &f);
+        let mut gc = GenContext { prog: &prog, names, f, out };
+        gen_stmt(f.node.clone(), &mut gc)?;
 
         // Epilogue
-        println!(".L.return.{}:", f.name);
-        println!("  mov r12, [rsp-8]");
-        println!("  mov r13, [rsp-16]");
-        println!("  mov r14, [rsp-24]");
-        println!("  mov r15, [rsp-32]");
-        println!("  mov rsp, rbp");
-        println!("  pop rbp");
-        println!("  ret");
+        emit!(out, "{}:", symbol(&format!(".L.return.{}", names.get(f.name))));
+        emit!(out, "  mov r12, [rsp-8]");
+        emit!(out, "  mov r13, [rsp-16]");
+        emit!(out, "  mov r14, [rsp-24]");
+        emit!(out, "  mov r15, [rsp-32]");
+        emit!(out, "  mov rsp, rbp");
+        emit!(out, "  pop rbp");
+        emit!(out, "  ret");
     }
+    Ok(out.to_string())
 }
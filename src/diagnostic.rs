@@ -0,0 +1,208 @@
+use super::tokenize::{ Span, SourceFile };
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+// Source range with a message shown under its underline
+#[derive(Debug, PartialEq, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+
+    pub primary: Option<Box<Label>>,    // Underlined with '^'
+    pub secondary: Vec<Label>,          // Underlined with '-'
+
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity,
+            message: message.into(),
+            primary: None,
+            secondary: vec![],
+            notes: vec![],
+            help: None,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Diagnostic::new(Severity::Error, message)
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Diagnostic::new(Severity::Warning, message)
+    }
+
+    pub fn with_primary(mut self, span: &Span, message: impl Into<String>) -> Self {
+        self.primary = Some(Box::new(Label { span: span.clone(), message: message.into() }));
+        self
+    }
+
+    pub fn with_secondary(mut self, span: &Span, message: impl Into<String>) -> Self {
+        self.secondary.push(Label { span: span.clone(), message: message.into() });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+}
+
+// Error returned by each compilation stage
+#[derive(Debug, PartialEq, Clone)]
+pub struct Error {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl From<Diagnostic> for Error {
+    fn from(diag: Diagnostic) -> Self {
+        Error { diagnostics: vec![diag] }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for diag in &self.diagnostics {
+            write!(f, "{}", diag)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {}
+
+// Width of the line number column
+fn gutter_width(labels: &[&Label]) -> usize {
+    labels.iter().map(|l| l.span.presented().1.to_string().len()).max().unwrap_or(0)
+}
+
+// Write the line containing the label and underline the labeled range.
+// The source line is omitted if the previous label was on the same line.
+fn write_snippet(f: &mut fmt::Formatter, label: &Label, mark: char, width: usize,
+                 prev: Option<&Label>) -> fmt::Result {
+    let span = &label.span;
+    let contents = &span.file.contents;
+
+    let start = span.start.min(contents.len());
+    let line_start = contents[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = contents[start..].find('\n').map_or(contents.len(), |i| start + i);
+    let line = &contents[line_start..line_end];
+
+    // Keep tabs so that the underline stays aligned with the source line.
+    let indent: String = contents[line_start..start].chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let end = span.end.clamp(start, line_end);
+    let len = contents[start..end].chars().count().max(1);
+
+    let mut underline = String::new();
+    underline.push(mark);
+    for _ in 1..len {
+        underline.push(if mark == '^' { '~' } else { mark });
+    }
+
+    let same_line = prev.is_some_and(|p| {
+        Rc::ptr_eq(&p.span.file, &span.file) && p.span.line == span.line
+    });
+    if !same_line {
+        if prev.is_some_and(|p| !Rc::ptr_eq(&p.span.file, &span.file)) {
+            writeln!(f, " {:>w$}--> {}", "", span, w = width)?;
+        }
+        writeln!(f, " {:>w$} | {}", span.presented().1, line, w = width)?;
+    }
+    write!(f, " {:>w$} | {}{}", "", indent, underline, w = width)?;
+    if !label.message.is_empty() {
+        write!(f, " {}", label.message)?;
+    }
+    writeln!(f)
+}
+
+// Write the chain of #include directives that led to the file, e.g.
+//
+//   In file included from a.h:1,
+//                    from main.c:2:
+fn write_include_chain(f: &mut fmt::Formatter, file: &SourceFile) -> fmt::Result {
+    let mut from = file.included_from.as_ref();
+    let mut prefix = "In file included from";
+    while let Some(span) = from {
+        from = span.file.included_from.as_ref();
+        let end = if from.is_some() { ',' } else { ':' };
+        let (name, line) = span.presented();
+        writeln!(f, "{} {}:{}{}", prefix, name, line, end)?;
+        prefix = "                 from";
+    }
+    Ok(())
+}
+
+// Render in the form of
+//
+//   file:line:col: error: message
+//    3 |   return x
+//      |          ^ label
+//      = note: ...
+//      = help: ...
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.primary {
+            Some(label) => {
+                write_include_chain(f, &label.span.file)?;
+                writeln!(f, "{}: {}: {}", label.span, self.severity, self.message)?
+            }
+            None => writeln!(f, "toycc: {}: {}", self.severity, self.message)?,
+        }
+
+        let labels: Vec<&Label> = self.primary.as_deref().into_iter().chain(self.secondary.iter()).collect();
+        let width = gutter_width(&labels);
+
+        let mut prev = None;
+        if let Some(label) = &self.primary {
+            write_snippet(f, label, '^', width, prev)?;
+            prev = Some(label);
+        }
+
+        for label in &self.secondary {
+            write_snippet(f, label, '-', width, prev)?;
+            prev = Some(label);
+        }
+
+        for note in &self.notes {
+            writeln!(f, " {:>w$} = note: {}", "", note, w = width)?;
+        }
+        if let Some(help) = &self.help {
+            writeln!(f, " {:>w$} = help: {}", "", help, w = width)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+// Interned identifiers. A Name is a small id for a string, so names can
+// be copied, compared and hashed cheaply.
+//
+// One Interner is shared by every stage of a compilation, and a Name only
+// means something to the Interner that created it.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Name::default() is the empty name.
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct Name(u32);
+
+#[derive(Debug)]
+pub struct Interner {
+    ids: HashMap<Rc<str>, Name>,
+    strings: Vec<Rc<str>>,
+}
+
+impl Interner {
+    pub fn new() -> Interner {
+        let mut interner = Interner { ids: HashMap::new(), strings: vec![] };
+        // Name(0) must be the empty name.
+        interner.intern("");
+        interner
+    }
+
+    pub fn intern(&mut self, s: &str) -> Name {
+        if let Some(&name) = self.ids.get(s) {
+            return name;
+        }
+        let s: Rc<str> = Rc::from(s);
+        let name = Name(self.strings.len() as u32);
+        self.strings.push(s.clone());
+        self.ids.insert(s, name);
+        name
+    }
+
+    pub fn get(&self, name: Name) -> &str {
+        &self.strings[name.0 as usize]
+    }
+}
+
+impl Default for Interner {
+    fn default() -> Interner {
+        Interner::new()
+    }
+}
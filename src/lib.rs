@@ -1,4 +1,8 @@
 pub mod codegen;
+pub mod diagnostic;
+pub mod intern;
 pub mod parse;
+pub mod preprocess;
 pub mod tokenize;
 pub mod types;
+pub mod unicode;
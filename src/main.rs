@@ -1,37 +1,168 @@
 extern crate toycc;
 
 use toycc::codegen::codegen;
-use toycc::tokenize::Lexer;
+use toycc::diagnostic::{ Diagnostic, Error };
+use toycc::intern::Interner;
+use toycc::tokenize::{ Lexer, SourceFile };
 use toycc::parse::parse;
+use toycc::types::{ size_of, align_of };
+use toycc::preprocess::{ preprocess, print_tokens, MacroOption, PreprocessOptions };
 
 use std::env;
+use std::fs;
+use std::io::{ self, Read };
+use std::path::PathBuf;
 use std::process;
 use std::iter::Iterator;
+use std::rc::Rc;
 
-fn align_to(n: usize, align: usize) -> usize {
-    (n + align - 1) / align * align
+struct Options {
+    pp: PreprocessOptions,          // -I, -D and -U
+    preprocess_only: bool,          // -E
+    input: String,                  // "-" for stdin
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+fn usage(status: i32) -> ! {
+    eprintln!("usage: toycc [-E] [-I <dir>] [-D <name>[=<value>]] [-U <name>] <file>");
+    eprintln!("  -E      print the preprocessed source instead of assembly");
+    eprintln!("  <file> may be '-' to read the source from stdin");
+    process::exit(status);
+}
 
-    if args.len() != 2 {
-        eprintln!("{}: invalid number of arguments", args[0]);
-        process::exit(1);
+// -DNAME defines NAME as 1.
+fn define_option(arg: &str) -> MacroOption {
+    match arg.split_once('=') {
+        Some((name, value)) => MacroOption::Define(name.to_string(), value.to_string()),
+        None => MacroOption::Define(arg.to_string(), "1".to_string()),
     }
+}
+
+fn parse_args(args: &[String]) -> Options {
+    let mut pp = PreprocessOptions::default();
+    let mut input = None;
+    let mut preprocess_only = false;
+
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+
+        if arg == "--help" {
+            usage(0);
+        }
+        if arg == "-E" {
+            preprocess_only = true;
+            continue;
+        }
+
+        // Options taking a value, either as "-Ivalue" or "-I value"
+        if let Some(opt) = ["-I", "-D", "-U"].iter().find(|o| arg.starts_with(*o)) {
+            let value = if arg.len() > 2 {
+                arg[2..].to_string()
+            } else {
+                match args.get(i) {
+                    Some(value) => value.clone(),
+                    None => usage(1),
+                }
+            };
+            if arg.len() == 2 {
+                i += 1;
+            }
+
+            match *opt {
+                "-I" => pp.include_paths.push(PathBuf::from(value)),
+                "-D" => pp.macros.push(define_option(&value)),
+                _ => pp.macros.push(MacroOption::Undef(value)),
+            }
+            continue;
+        }
+        if arg.starts_with('-') && arg != "-" {
+            eprintln!("toycc: unknown argument: {}", arg);
+            usage(1);
+        }
+        if input.is_some() {
+            eprintln!("toycc: multiple input files are not supported");
+            usage(1);
+        }
+        input = Some(arg.clone());
+    }
+
+    match input {
+        Some(input) => Options { pp, preprocess_only, input },
+        None => usage(1),
+    }
+}
 
-    let lexer = Lexer::new(&args[1]);
-    let tokens = lexer.tokenize();
-    let mut prog = parse(tokens);
+fn read_file(path: &str) -> Result<Rc<SourceFile>, Error> {
+    let read = if path == "-" {
+        let mut buf = String::new();
+        io::stdin().read_to_string(&mut buf).map(|_| buf)
+    } else {
+        fs::read_to_string(path)
+    };
+
+    match read {
+        Ok(contents) => {
+            let name = if path == "-" { "<stdin>" } else { path };
+            Ok(SourceFile::new(name, contents))
+        }
+        Err(e) => Err(Diagnostic::error(format!("cannot open {}: {}", path, e)).into()),
+    }
+}
+
+fn align_to(n: usize, align: usize) -> usize {
+    n.div_ceil(align) * align
+}
+
+// Warnings are appended to `warnings` even if compilation fails.
+fn compile(opts: &Options, warnings: &mut Vec<Diagnostic>) -> Result<String, Error> {
+    let file = read_file(&opts.input)?;
+    let mut names = Interner::new();
+    let lexer = Lexer::new(file, &mut names);
+    let tokens = lexer.tokenize()?;
+    let tokens = preprocess(tokens, &opts.pp, &mut names, warnings)?;
+    if opts.preprocess_only {
+        return Ok(print_tokens(&tokens));
+    }
+
+    let mut prog = parse(tokens, &mut names)?;
 
     for i in 0..prog.functions.len() {
-        let mut offset = 32;
+        let mut offset: usize = 32;
         for l in &mut prog.functions[i].locals {
-            offset += 8;
+            // Stack offsets are 32-bit displacements in instructions.
+            offset = match offset.checked_add(size_of(&l.ty)) {
+                Some(end) if end <= i32::MAX as usize - 16 => align_to(end, align_of(&l.ty)),
+                _ => {
+                    let mut diag = Diagnostic::error("stack frame is too large");
+                    if let Some(span) = &l.span {
+                        diag = diag.with_primary(span, "this variable does not fit in the stack frame");
+                    }
+                    return Err(diag.into());
+                }
+            };
             l.offset = offset;
         }
         prog.functions[i].stack_size = align_to(offset, 16);
     }
 
-    codegen(prog);
+    codegen(prog, &names)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let opts = parse_args(&args);
+
+    let mut warnings = vec![];
+    let res = compile(&opts, &mut warnings);
+    for diag in &warnings {
+        eprint!("{}", diag);
+    }
+    match res {
+        Ok(asm) => print!("{}", asm),
+        Err(e) => {
+            eprint!("{}", e);
+            process::exit(1);
+        }
+    }
 }
@@ -1,39 +1,51 @@
-use super::tokenize::{ Token, TokenKind, Keyword, Symbol };
-use super::types::{ Type, add_type, is_integer, ty_int, pointer_to, func_type, copy_type };
-use std::process::exit;
+use super::tokenize::{ Token, TokenKind, Keyword, Symbol, Span, IntType };
+use super::types::{ Type, TypeKind, add_type, get_common_type, is_compatible, is_integer, size_of, ty_char, ty_short, ty_int, ty_long, ty_long_long, pointer_to, array_of, func_type, copy_type };
+use super::diagnostic::{ Diagnostic, Error };
+use super::intern::{ Interner, Name };
+use std::collections::HashMap;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Default, PartialEq, Clone)]
 pub enum NodeKind {
     Num,        // Integer
     Add,        // +
     Sub,        // -
     Mul,        // *
     Div,        // /
+    Mod,        // %
+    Shl,        // <<
+    Shr,        // >>
+    BitAnd,     // &
+    BitOr,      // |
+    BitXor,     // ^
+    BitNot,     // ~
     Equal,      // ==
     Ne,         // !=
     Lt,         // <
     Le,         // <=
+    LogAnd,     // &&
+    LogOr,      // ||
+    Not,        // !
     If,         // "if"
     For,        // "for"
     Block,      // { ... }
     ExprStmt,   // Expression statement
     Return,     // Return statement
     Assign,     // =
+    Comma,      // ,
+    Cond,       // ?:
     Addr,       // &
     Deref,      // *
     Var,        // Variable
     Funcall,    // Function call
+    #[default]
     Null,       // Default value of NodeKind
 }
 
-impl Default for NodeKind {
-    fn default() -> Self { NodeKind::Null }
-}
-
 #[derive(Debug, Default, PartialEq, Clone)]
 pub struct Node {
     pub kind: NodeKind,             // Node kind
     pub ty: Type,                   // Type, e.g. int or pointer to int
+    pub span: Option<Span>,         // Representative token location
 
     pub lhs: Option<Box<Node>>,     // Left-hand side
     pub rhs: Option<Box<Node>>,     // Right-hand side
@@ -49,547 +61,1294 @@ pub struct Node {
     pub body: Option<Vec<Box<Node>>>,
 
     // Function call
-    pub funcname: String,
+    pub funcname: Name,
     pub args: Option<Vec<Box<Node>>>,
 
-    pub var: Option<usize>,         // Used if kind == NodeKind::Var
+    pub var: Option<VarRef>,        // Used if kind == NodeKind::Var
     pub val: i64,                   // Used if kind == NodeKind::Num
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct Var {
-    pub name: String,
+    pub name: Name,
     pub ty: Type,
+    pub span: Option<Span>,             // Declaration
+    pub offset: usize,                  // Local variable
+
+    // Global variable
+    pub init_data: Option<Vec<u8>>,
+    pub rel: Vec<Relocation>,
+    pub is_readonly: bool,              // String literal
+
+    // Function, whose type is TypeKind::Func
+    pub has_body: bool,                 // Definition rather than a prototype
+}
+
+// A pointer in init_data initialized with the address of a global,
+// i.e. `label + addend`
+#[derive(Debug, Clone)]
+pub struct Relocation {
     pub offset: usize,
+    pub label: Name,
+    pub addend: i64,
 }
 
-fn find_var(pc: &mut ParseContext) -> Option<usize> {
-    for (i, var) in pc.locals.iter().enumerate() {
-        if pc.tokens[pc.pos].get_string() == var.name {
-            return Some(i);
-        }
-    }
-    None 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum VarRef {
+    Local(usize),       // Index of Function::locals
+    Global(usize),      // Index of Program::globals
 }
 
-fn new_binary(kind: NodeKind, lhs: Box<Node>, rhs: Box<Node>) -> Node {
+// Look up a variable from the innermost scope outward. Globals are
+// in the outermost scope.
+fn find_var(pc: &mut ParseContext) -> Option<VarRef> {
+    let name = pc.tokens[pc.pos].get_ident();
+    pc.scopes.iter().rev()
+        .find_map(|scope| scope.get(&name))
+        .map(|&i| VarRef::Local(i))
+        .or_else(|| pc.global_names.get(&name)
+            .filter(|&&i| pc.globals[i].ty.kind != TypeKind::Func)
+            .map(|&i| VarRef::Global(i)))
+}
+
+fn enter_scope(pc: &mut ParseContext) {
+    pc.scopes.push(HashMap::new());
+}
+
+fn leave_scope(pc: &mut ParseContext) {
+    pc.scopes.pop();
+}
+
+fn new_node(kind: NodeKind, span: &Span) -> Node {
     Node {
         kind,
+        span: Some(span.clone()),
+        ..Default::default()
+    }
+}
+
+fn new_binary(kind: NodeKind, lhs: Box<Node>, rhs: Box<Node>, span: &Span) -> Node {
+    Node {
         lhs: Some(lhs),
         rhs: Some(rhs),
-        ..Default::default()
+        ..new_node(kind, span)
     }
 }
 
-fn new_unary(kind: NodeKind, expr: Box<Node>) -> Node {
+fn new_unary(kind: NodeKind, expr: Box<Node>, span: &Span) -> Node {
     Node {
-        kind,
         lhs: Some(expr),
-        ..Default::default()
+        ..new_node(kind, span)
     }
 }
 
-fn get_number(val: i64) -> Node {
+fn get_number(val: i64, span: &Span) -> Node {
     Node {
-        kind: NodeKind::Num,
         val,
-        ..Default::default()
+        ..new_node(NodeKind::Num, span)
     }
 }
 
-fn new_num(pc: &mut ParseContext) -> Node {
-    if pc.tokens[pc.pos].kind.is_num() {
-        let val = pc.tokens[pc.pos].get_num();
-        return get_number(val);
+fn new_num(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let tok = &pc.tokens[pc.pos];
+    if let TokenKind::Num(val, ity) = tok.kind {
+        let ty = match ity {
+            IntType::Int => ty_int(),
+            IntType::UInt => Type { is_unsigned: true, ..ty_int() },
+            IntType::Long => ty_long(),
+            IntType::ULong => Type { is_unsigned: true, ..ty_long() },
+            IntType::LongLong => ty_long_long(),
+            IntType::ULongLong => Type { is_unsigned: true, ..ty_long_long() },
+        };
+        return Ok(Node { ty, ..get_number(val, tok.loc()) });
     }
-    panic!("number expected, but got {}", pc.tokens[pc.pos].get_string());
+    Err(Diagnostic::error(format!("expected an expression, found {}", tok))
+        .with_primary(tok.loc(), "expected an expression"))
 }
 
-fn new_var_node(var: usize) -> Node {
+fn new_var_node(pc: &ParseContext, var: VarRef, span: &Span) -> Node {
+    let ty = match var {
+        VarRef::Local(i) => &pc.locals[i].ty,
+        VarRef::Global(i) => &pc.globals[i].ty,
+    };
     Node {
-        kind: NodeKind::Var,
         var: Some(var),
-        ..Default::default()
+        ty: Type { name: None, ..ty.clone() },
+        ..new_node(NodeKind::Var, span)
+    }
+}
+
+// Add a local variable to the innermost scope. A name can be declared
+// only once per scope, but may shadow one in an enclosing scope.
+fn push_lvar(pc: &mut ParseContext, var: Var) -> Result<usize, Diagnostic> {
+    let i = pc.locals.len();
+    if var.name != Name::default() {
+        let scope = pc.scopes.last_mut().unwrap();
+        if let Some(&prev) = scope.get(&var.name) {
+            let mut diag = Diagnostic::error(format!("redefinition of '{}'", pc.names.get(var.name)));
+            if let Some(span) = &var.span {
+                diag = diag.with_primary(span, "redefined here");
+            }
+            if let Some(span) = &pc.locals[prev].span {
+                diag = diag.with_secondary(span, "previous definition is here");
+            }
+            return Err(diag);
+        }
+        scope.insert(var.name, i);
     }
+    pc.locals.push(var);
+    Ok(i)
 }
 
-fn new_lvar_parms(pc: &mut ParseContext, t: Type) {
+fn new_lvar_parms(pc: &mut ParseContext, t: Type) -> Result<(), Diagnostic> {
     let ty = t.clone();
-    let name = t.name.unwrap().get_string();
+    let tok = t.name.unwrap();
 
     let v = Var {
-        name,
+        name: tok.get_ident(),
         ty,
+        span: Some(tok.loc().clone()),
         ..Default::default()
     };
-    pc.locals.push(v);
+    push_lvar(pc, v)?;
+    Ok(())
+}
+
+// Create an unnamed local variable for a compiler-generated temporary
+fn new_tmp_lvar(pc: &mut ParseContext, ty: Type) -> usize {
+    // Unnamed variables never conflict.
+    push_lvar(pc, Var { ty, ..Default::default() }).unwrap()
 }
 
-fn new_lvar(pc: &mut ParseContext, ty: Type) -> usize {
+// Create a local variable named by the declarator of ty
+fn new_lvar(pc: &mut ParseContext, ty: Type) -> Result<usize, Diagnostic> {
+    let tok = ty.name.clone().unwrap();
     let v = Var {
-        name: pc.tokens[pc.pos].get_string(),
-        ty,
+        name: tok.get_ident(),
+        ty: Type { name: None, ..ty },
+        span: Some(tok.loc().clone()),
         ..Default::default()
     };
-    pc.locals.push(v);
-    return pc.locals.len()-1;
+    push_lvar(pc, v)
+}
+
+// Location of the current token
+fn span(pc: &ParseContext) -> Span {
+    pc.tokens[pc.pos].loc().clone()
 }
 
 
 // stmt = "return" expr ";"
 //      | "{" compound-stmt
 //      | "if" "(" expr ")" stmt ("else" stmt)?
-//      | "for" "(" expr? ";" expr? ";" expr? ")" stmt
+//      | for-stmt
 //      | "while" "(" expr ")" stmt
 //      | expr-stmt
-fn stmt(pc: &mut ParseContext) -> Node {
+fn stmt(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let start = span(pc);
     match pc.tokens[pc.pos].kind {
         // "return" statement
         TokenKind::Keyword(Keyword::Return) => {
             pc.pos += 1;
-            let lhs = expr(pc);
-            let node = new_unary(NodeKind::Return, Box::new(lhs));
-            skip(pc, TokenKind::Symbol(Symbol::Semicolon));
-            return node;
+            let lhs = expr(pc)?;
+            let node = new_unary(NodeKind::Return, Box::new(lhs), &start);
+            skip(pc, TokenKind::Symbol(Symbol::Semicolon))?;
+            Ok(node)
         }
         // "if" statement
         TokenKind::Keyword(Keyword::If) => {
-            let mut node = Node { kind: NodeKind::If, ..Default::default() };
+            let mut node = new_node(NodeKind::If, &start);
 
             pc.pos += 1;
-            skip(pc, TokenKind::Symbol(Symbol::OpeningParen));
+            skip(pc, TokenKind::Symbol(Symbol::OpeningParen))?;
 
             // set cond
-            let cond = expr(pc);
+            let cond = expr(pc)?;
             node.cond = Some(Box::new(cond));
 
-            skip(pc, TokenKind::Symbol(Symbol::ClosingParen));
+            skip(pc, TokenKind::Symbol(Symbol::ClosingParen))?;
 
             // set then
-            let then = stmt(pc);
+            let then = stmt(pc)?;
             node.then = Some(Box::new(then));
 
             // "else"
             if pc.tokens[pc.pos].kind == TokenKind::Keyword(Keyword::Else) {
                 pc.pos += 1;
-                let t = stmt(pc);
+                let t = stmt(pc)?;
                 node.els = Some(Box::new(t));
             }
 
-            return node;
+            Ok(node)
         }
         // "for" statement
         TokenKind::Keyword(Keyword::For) => {
-            let mut node = Node { kind: NodeKind::For, ..Default::default() };
-
-            pc.pos += 1;
-            skip(pc, TokenKind::Symbol(Symbol::OpeningParen));
-
-            // initとincは値を返さない
-            // init
-            if pc.tokens[pc.pos].kind != TokenKind::Symbol(Symbol::Semicolon) {
-                let init = expr(pc);
-                node.init = Some(Box::new(new_unary(NodeKind::ExprStmt, Box::new(init))));
-            }
-            skip(pc, TokenKind::Symbol(Symbol::Semicolon));
-
-            // cond
-            if pc.tokens[pc.pos].kind != TokenKind::Symbol(Symbol::Semicolon) {
-                let cond = expr(pc);
-                node.cond = Some(Box::new(cond));
-            }
-            skip(pc, TokenKind::Symbol(Symbol::Semicolon));
-
-            if pc.tokens[pc.pos].kind != TokenKind::Symbol(Symbol::ClosingParen) {
-                let inc = expr(pc);
-                node.inc = Some(Box::new(new_unary(NodeKind::ExprStmt, Box::new(inc))));
-            }
-            skip(pc, TokenKind::Symbol(Symbol::ClosingParen));
-
-            let then = stmt(pc);
-            node.then = Some(Box::new(then));
-
-            return node;
+            // Variables declared in init are visible only in the loop.
+            enter_scope(pc);
+            let node = for_stmt(pc);
+            leave_scope(pc);
+            node
         }
         // "while" statement
         TokenKind::Keyword(Keyword::While) => {
-            let mut node = Node { kind: NodeKind::For, ..Default::default() };
+            let mut node = new_node(NodeKind::For, &start);
             pc.pos += 1;
-            skip(pc, TokenKind::Symbol(Symbol::OpeningParen));
+            skip(pc, TokenKind::Symbol(Symbol::OpeningParen))?;
 
-            let cond = expr(pc);
+            let cond = expr(pc)?;
             node.cond = Some(Box::new(cond));
-            skip(pc, TokenKind::Symbol(Symbol::ClosingParen));
+            skip(pc, TokenKind::Symbol(Symbol::ClosingParen))?;
 
-            let then = stmt(pc);
+            let then = stmt(pc)?;
             node.then = Some(Box::new(then));
 
-            return node;
+            Ok(node)
         }
         // "{...}" compound statement
         TokenKind::Symbol(Symbol::OpeningBrace) => {
             pc.pos += 1;
-            let body = compound_stmt(pc);
-            return body;
+            enter_scope(pc);
+            let node = compound_stmt(pc);
+            leave_scope(pc);
+            node
         }
         _ => expr_stmt(pc)
     }
 }
 
+// for-stmt = "for" "(" (declaration | expr? ";") expr? ";" expr? ")" stmt
+fn for_stmt(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let start = span(pc);
+    let mut node = new_node(NodeKind::For, &start);
+
+    pc.pos += 1;
+    skip(pc, TokenKind::Symbol(Symbol::OpeningParen))?;
+
+    // initとincは値を返さない
+    // init
+    if is_typename(pc) {
+        node.init = Some(Box::new(declaration(pc)?));
+    } else {
+        if pc.tokens[pc.pos].kind != TokenKind::Symbol(Symbol::Semicolon) {
+            let init = expr(pc)?;
+            node.init = Some(Box::new(new_unary(NodeKind::ExprStmt, Box::new(init), &start)));
+        }
+        skip(pc, TokenKind::Symbol(Symbol::Semicolon))?;
+    }
+
+    // cond
+    if pc.tokens[pc.pos].kind != TokenKind::Symbol(Symbol::Semicolon) {
+        let cond = expr(pc)?;
+        node.cond = Some(Box::new(cond));
+    }
+    skip(pc, TokenKind::Symbol(Symbol::Semicolon))?;
+
+    if pc.tokens[pc.pos].kind != TokenKind::Symbol(Symbol::ClosingParen) {
+        let inc = expr(pc)?;
+        node.inc = Some(Box::new(new_unary(NodeKind::ExprStmt, Box::new(inc), &start)));
+    }
+    skip(pc, TokenKind::Symbol(Symbol::ClosingParen))?;
+
+    let then = stmt(pc)?;
+    node.then = Some(Box::new(then));
+
+    Ok(node)
+}
+
 // compound-stmt = (declaration | stmt)* "}"
-fn compound_stmt(pc: &mut ParseContext) -> Node {
-    let mut node = Node { kind: NodeKind::Block, ..Default::default() };
+fn compound_stmt(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = new_node(NodeKind::Block, &span(pc));
+    let open = pc.tokens[pc.pos-1].loc().clone();
 
     let mut body: Vec<Box<Node>> = vec![];
     while pc.tokens[pc.pos].kind != TokenKind::Symbol(Symbol::ClosingBrace) {
-        if pc.tokens[pc.pos].kind == TokenKind::Keyword(Keyword::Int) {
-            let mut node = declaration(pc);
-            body.push(Box::new(add_type(&mut node)));
+        if pc.tokens[pc.pos].kind == TokenKind::Eof {
+            return Err(Diagnostic::error("expected '}', found end of file")
+                .with_primary(&span(pc), "expected '}'")
+                .with_secondary(&open, "unclosed delimiter"));
+        }
+
+        let res = if is_typename(pc) {
+            declaration(pc)
         } else {
-            let mut node = stmt(pc);
-            body.push(Box::new(add_type(&mut node)));
+            stmt(pc)
+        };
+
+        match res {
+            Ok(mut node) => body.push(Box::new(add_type(&mut node))),
+            Err(diag) => {
+                pc.errors.push(diag);
+                synchronize(pc);
+            }
         }
     }
 
     node.body = Some(body);
     pc.pos += 1;
-    return node;
+    Ok(node)
 }
 
-// funcdef = typespec declarator "{" compound-stmt
-fn funcdef(pc: &mut ParseContext) -> Function {
+// funcdef = typespec declarator ("{" compound-stmt | ";")
+fn funcdef(pc: &mut ParseContext) -> Result<Option<Function>, Diagnostic> {
     pc.locals = Vec::new();
-    let ty = typespec(pc);
-    let ty = declarator(pc, ty);
+    let ty = typespec(pc)?;
+    let ty = declarator(pc, ty)?;
+    let tok = ty.name.clone().unwrap();
+    let name = tok.get_ident();
+
+    // Declare the function before the body so that recursive calls are
+    // typed too. `int f { ... }` has no parameter list.
+    let return_ty = match &ty.return_ty {
+        Some(return_ty) => *return_ty.clone(),
+        None => Type { name: None, ..ty.clone() },
+    };
+    let has_body = pc.tokens[pc.pos].kind != TokenKind::Symbol(Symbol::Semicolon);
+    new_gvar(pc, Var {
+        name,
+        ty: func_type(return_ty),
+        span: Some(tok.loc().clone()),
+        has_body,
+        ..Default::default()
+    })?;
 
-    skip(pc, TokenKind::Symbol(Symbol::OpeningBrace));
+    // A prototype only declares the return type.
+    if !has_body {
+        pc.pos += 1;
+        return Ok(None);
+    }
 
+    // Parameters are in the same scope as the outermost block.
+    pc.scopes = vec![HashMap::new()];
     for t in ty.params {
-        new_lvar_parms(pc, t);
+        new_lvar_parms(pc, t)?;
     }
+
+    skip(pc, TokenKind::Symbol(Symbol::OpeningBrace))?;
     let params = pc.locals.clone();
 
-    let node = compound_stmt(pc);
+    let node = compound_stmt(pc)?;
     let locals = pc.locals.clone();
 
-    Function {
-        name: ty.name.unwrap().get_string(),
+    Ok(Some(Function {
+        name,
         node,
         params,
         locals,
         ..Default::default()
+    }))
+}
+
+// Lookahead tokens to find out whether the next top-level item is a
+// function definition or a global variable.
+fn is_function(pc: &mut ParseContext) -> bool {
+    let pos = pc.pos;
+    let res = typespec(pc).and_then(|ty| declarator(pc, ty));
+    // `int f { ... }` is a function without a parameter list.
+    let body = pc.tokens[pc.pos].kind == TokenKind::Symbol(Symbol::OpeningBrace);
+    pc.pos = pos;
+    // Let funcdef report syntax errors.
+    !matches!(res, Ok(ty) if ty.kind != TypeKind::Func && !body)
+}
+
+// global-var = typespec (declarator ("=" gvar-initializer)? ("," declarator ("=" gvar-initializer)?)*)? ";"
+fn global_variable(pc: &mut ParseContext) -> Result<(), Diagnostic> {
+    // Local variables are not visible at file scope.
+    pc.scopes.clear();
+    let basety = typespec(pc)?;
+
+    let mut i = 0;
+    while !consume(pc, TokenKind::Symbol(Symbol::Semicolon)) {
+        if i > 0 {
+            skip(pc, TokenKind::Symbol(Symbol::Comma))?;
+        }
+        i += 1;
+
+        let ty = declarator(pc, basety.clone())?;
+        let tok = ty.name.clone().unwrap();
+        let mut var = Var {
+            name: tok.get_ident(),
+            ty: Type { name: None, ..ty },
+            span: Some(tok.loc().clone()),
+            ..Default::default()
+        };
+        if consume(pc, TokenKind::Symbol(Symbol::Assign)) {
+            gvar_initializer(pc, &mut var)?;
+        }
+        new_gvar(pc, var)?;
+    }
+    Ok(())
+}
+
+// gvar-initializer = assign
+fn gvar_initializer(pc: &mut ParseContext, var: &mut Var) -> Result<(), Diagnostic> {
+    if var.ty.kind == TypeKind::Array {
+        return Err(array_initializer(pc.tokens[pc.pos-1].loc()));
+    }
+    let mut node = assign(pc)?;
+    let node = add_type(&mut node);
+
+    let mut label = None;
+    let val = eval2(pc, &node, Some(&mut label))?;
+    let size = size_of(&var.ty);
+    var.init_data = Some(val.to_le_bytes()[..size].to_vec());
+    if let Some(label) = label {
+        // The linker fills in a whole 64-bit address.
+        if var.ty.base.is_none() {
+            return Err(eval_error(&node, "an address can only initialize a pointer")
+                .with_note(format!("'{}' is not a pointer", pc.names.get(var.name))));
+        }
+        var.rel.push(Relocation { offset: 0, label, addend: val });
+    }
+    Ok(())
+}
+
+fn is_definition(var: &Var) -> bool {
+    var.init_data.is_some() || var.has_body
+}
+
+// Add a global variable or function. `int x; int x = 1;` is allowed
+// because a declaration without an initializer is only a tentative
+// definition, and so is a prototype followed by the function.
+fn new_gvar(pc: &mut ParseContext, var: Var) -> Result<(), Diagnostic> {
+    let i = match pc.global_names.get(&var.name) {
+        Some(&i) => i,
+        None => {
+            pc.global_names.insert(var.name, pc.globals.len());
+            pc.globals.push(var);
+            return Ok(());
+        }
+    };
+
+    let prev = &mut pc.globals[i];
+    let is_func = |v: &Var| v.ty.kind == TypeKind::Func;
+    let msg = if is_func(prev) != is_func(&var) || (is_definition(prev) && is_definition(&var)) {
+        "redefinition of"
+    } else if prev.ty != var.ty {
+        "conflicting types for"
+    } else {
+        if is_definition(&var) {
+            *prev = var;
+        }
+        return Ok(());
+    };
+
+    let mut diag = Diagnostic::error(format!("{} '{}'", msg, pc.names.get(var.name)));
+    if let Some(span) = &var.span {
+        diag = diag.with_primary(span, "");
+    }
+    if let Some(span) = &prev.span {
+        diag = diag.with_secondary(span, "previous definition is here");
+    }
+    Err(diag)
+}
+
+fn eval(pc: &ParseContext, node: &Node) -> Result<i64, Diagnostic> {
+    eval2(pc, node, None)
+}
+
+// Evaluate a constant expression. If `label` is given, the value may
+// also be the address of a global variable plus a constant. The name of
+// the variable is stored to `label` and the constant is returned.
+fn eval2(pc: &ParseContext, node: &Node, label: Option<&mut Option<Name>>) -> Result<i64, Diagnostic> {
+    let lhs = node.lhs.as_deref();
+    let rhs = node.rhs.as_deref();
+    let operand = |n: Option<&Node>| eval(pc, n.unwrap());
+    let is_unsigned = || get_common_type(&lhs.unwrap().ty, &rhs.unwrap().ty).is_unsigned;
+
+    // An array decays to the address of its first element.
+    if node.ty.kind == TypeKind::Array {
+        return eval_rval(pc, node, label);
+    }
+
+    let val = match node.kind {
+        NodeKind::Num => node.val,
+        NodeKind::Add => eval2(pc, lhs.unwrap(), label)?.wrapping_add(operand(rhs)?),
+        NodeKind::Sub => eval2(pc, lhs.unwrap(), label)?.wrapping_sub(operand(rhs)?),
+        NodeKind::Mul => operand(lhs)?.wrapping_mul(operand(rhs)?),
+        NodeKind::Div | NodeKind::Mod => {
+            let (a, b) = (operand(lhs)?, operand(rhs)?);
+            if b == 0 {
+                return Err(eval_error(node, "division by zero in initializer"));
+            }
+            match (node.kind == NodeKind::Div, is_unsigned()) {
+                (true, true) => ((a as u64) / (b as u64)) as i64,
+                (true, false) => a.wrapping_div(b),
+                (false, true) => ((a as u64) % (b as u64)) as i64,
+                (false, false) => a.wrapping_rem(b),
+            }
+        }
+        NodeKind::Shl => operand(lhs)?.wrapping_shl(operand(rhs)? as u32),
+        NodeKind::Shr if node.ty.is_unsigned => (operand(lhs)? as u64).wrapping_shr(operand(rhs)? as u32) as i64,
+        NodeKind::Shr => operand(lhs)?.wrapping_shr(operand(rhs)? as u32),
+        NodeKind::BitAnd => operand(lhs)? & operand(rhs)?,
+        NodeKind::BitOr => operand(lhs)? | operand(rhs)?,
+        NodeKind::BitXor => operand(lhs)? ^ operand(rhs)?,
+        NodeKind::BitNot => !operand(lhs)?,
+        NodeKind::Equal => (operand(lhs)? == operand(rhs)?) as i64,
+        NodeKind::Ne => (operand(lhs)? != operand(rhs)?) as i64,
+        NodeKind::Lt if is_unsigned() => ((operand(lhs)? as u64) < (operand(rhs)? as u64)) as i64,
+        NodeKind::Lt => (operand(lhs)? < operand(rhs)?) as i64,
+        NodeKind::Le if is_unsigned() => ((operand(lhs)? as u64) <= (operand(rhs)? as u64)) as i64,
+        NodeKind::Le => (operand(lhs)? <= operand(rhs)?) as i64,
+        NodeKind::LogAnd => (operand(lhs)? != 0 && operand(rhs)? != 0) as i64,
+        NodeKind::LogOr => (operand(lhs)? != 0 || operand(rhs)? != 0) as i64,
+        NodeKind::Not => (operand(lhs)? == 0) as i64,
+        NodeKind::Cond => {
+            let branch = if operand(node.cond.as_deref())? != 0 { &node.then } else { &node.els };
+            eval2(pc, branch.as_deref().unwrap(), label)?
+        }
+        NodeKind::Comma => {
+            operand(lhs)?;
+            eval2(pc, rhs.unwrap(), label)?
+        }
+        NodeKind::Addr => eval_rval(pc, lhs.unwrap(), label)?,
+        _ => return Err(eval_error(node, NOT_CONSTANT)),
+    };
+
+    // Wrap around to the width of the result type.
+    let val = match (size_of(&node.ty), node.ty.is_unsigned) {
+        (1, false) => val as i8 as i64,
+        (1, true) => val as u8 as i64,
+        (2, false) => val as i16 as i64,
+        (2, true) => val as u16 as i64,
+        (4, false) => val as i32 as i64,
+        (4, true) => val as u32 as i64,
+        _ => val,
+    };
+    Ok(val)
+}
+
+// Evaluate the address of an lvalue, e.g. `x` in `&x` or `g[1]` in `&g[1]`.
+fn eval_rval(pc: &ParseContext, node: &Node, label: Option<&mut Option<Name>>) -> Result<i64, Diagnostic> {
+    match (node.kind.clone(), node.var, label) {
+        (NodeKind::Var, Some(VarRef::Global(i)), Some(label)) => {
+            *label = Some(pc.globals[i].name);
+            Ok(0)
+        }
+        (NodeKind::Deref, _, label) => eval2(pc, node.lhs.as_deref().unwrap(), label),
+        _ => Err(eval_error(node, NOT_CONSTANT)),
     }
 }
 
+const NOT_CONSTANT: &str = "initializer element is not a compile-time constant";
+
+fn eval_error(node: &Node, msg: &str) -> Diagnostic {
+    let mut diag = Diagnostic::error(msg);
+    if let Some(span) = &node.span {
+        diag = diag.with_primary(span, "");
+    }
+    diag
+}
+
 // declaration = typespec (declarator ("=" expr)? ("," declarator ("=" expr)?)*)? ";"
-fn declaration(pc: &mut ParseContext) -> Node {
-    let basety = typespec(pc);
+fn declaration(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let start = span(pc);
+    let basety = typespec(pc)?;
 
     let mut body: Vec<Box<Node>> = vec![];
     let mut i = 0;
     loop {
         if pc.tokens[pc.pos].kind == TokenKind::Symbol(Symbol::Semicolon) {
-            skip(pc, TokenKind::Symbol(Symbol::Semicolon));
+            skip(pc, TokenKind::Symbol(Symbol::Semicolon))?;
             break;
         }
 
-        if i > 0 {
-            skip(pc, TokenKind::Symbol(Symbol::Comma));
+        // Neither "," nor ";"
+        if i > 0 && !consume(pc, TokenKind::Symbol(Symbol::Comma)) {
+            skip(pc, TokenKind::Symbol(Symbol::Semicolon))?;
         }
         i += 1;
 
-        let ty = declarator(pc, basety.clone());
-        pc.pos -= 1;
-        let var = new_lvar(pc, ty);
+        let ty = declarator(pc, basety.clone())?;
+        let name = ty.name.as_ref().unwrap().loc().clone();
+        let var = new_lvar(pc, ty)?;
 
-        pc.pos += 1;
         if pc.tokens[pc.pos].kind != TokenKind::Symbol(Symbol::Assign) { continue; }
 
-        let lhs = new_var_node(var);
+        let lhs = new_var_node(pc, VarRef::Local(var), &name);
+        let op = span(pc);
+        if lhs.ty.kind == TypeKind::Array {
+            return Err(array_initializer(&op));
+        }
         pc.pos += 1;
-        let rhs = assign(pc);
-        let node = new_binary(NodeKind::Assign, Box::new(lhs), Box::new(rhs));
-        body.push(Box::new(new_unary(NodeKind::ExprStmt, Box::new(node))));
+        let rhs = assign(pc)?;
+        let node = new_binary(NodeKind::Assign, Box::new(lhs), Box::new(rhs), &op);
+        body.push(Box::new(new_unary(NodeKind::ExprStmt, Box::new(node), &op)));
     }
 
 
-    let mut node = Node { kind: NodeKind::Block, ..Default::default() };
+    let mut node = new_node(NodeKind::Block, &start);
     node.body = Some(body);
-    node
+    Ok(node)
 }
 
-// typespec = "int"
-fn typespec(pc: &mut ParseContext) -> Type {
-    skip(pc, TokenKind::Keyword(Keyword::Int));
-    ty_int()
+// typespec = "char" | "short" "int"? | "int" | "long" "long"? "int"?
+fn typespec(pc: &mut ParseContext) -> Result<Type, Diagnostic> {
+    if consume(pc, TokenKind::Keyword(Keyword::Char)) {
+        return Ok(ty_char());
+    }
+    if consume(pc, TokenKind::Keyword(Keyword::Short)) {
+        consume(pc, TokenKind::Keyword(Keyword::Int));
+        return Ok(ty_short());
+    }
+    if consume(pc, TokenKind::Keyword(Keyword::Long)) {
+        let ty = if consume(pc, TokenKind::Keyword(Keyword::Long)) { ty_long_long() } else { ty_long() };
+        consume(pc, TokenKind::Keyword(Keyword::Int));
+        return Ok(ty);
+    }
+    skip(pc, TokenKind::Keyword(Keyword::Int))?;
+    Ok(ty_int())
+}
+
+// 型名で始まる場合、真を返す
+fn is_typename(pc: &ParseContext) -> bool {
+    matches!(pc.tokens[pc.pos].kind, TokenKind::Keyword(Keyword::Char | Keyword::Short | Keyword::Int | Keyword::Long))
 }
 
 // declarator = "*"* ident type-suffix
-fn declarator(pc: &mut ParseContext, mut ty: Type) -> Type {
+fn declarator(pc: &mut ParseContext, mut ty: Type) -> Result<Type, Diagnostic> {
     loop {
         if !consume(pc, TokenKind::Symbol(Symbol::Asterisk)) { break; }
         ty = pointer_to(ty);
     }
 
     if !pc.tokens[pc.pos].kind.is_identifier() {
-        eprintln!("expected a variable name: {}", pc.tokens[pc.pos].get_string());
+        let tok = &pc.tokens[pc.pos];
+        return Err(Diagnostic::error(format!("expected a variable name, found {}", tok))
+            .with_primary(tok.loc(), "expected a variable name"));
     }
 
     let pos = pc.pos;
     let name = Some(pc.tokens[pos].clone());
     pc.pos += 1;
-    let mut ty = type_suffix(pc, ty);
+    let mut ty = type_suffix(pc, ty)?;
     ty.name = name;
-    ty
+    Ok(ty)
 }
 
-// type-suffix = ( "(" func-params? ")" )
+// type-suffix = "(" func-params? ")"
+//             | "[" num "]" type-suffix
+//             | ε
 // func-params = param ("," param)*
 // param       = typespec declarator
-fn type_suffix(pc: &mut ParseContext, mut ty: Type) -> Type {
+fn type_suffix(pc: &mut ParseContext, mut ty: Type) -> Result<Type, Diagnostic> {
+    if consume(pc, TokenKind::Symbol(Symbol::OpeningBracket)) {
+        let tok = pc.tokens[pc.pos].clone();
+        let len = match tok.kind {
+            TokenKind::Num(len, _) if len >= 0 => len as usize,
+            _ => {
+                return Err(Diagnostic::error(format!("expected an array size, found {}", tok))
+                    .with_primary(tok.loc(), "expected an array size"));
+            }
+        };
+        pc.pos += 1;
+        skip(pc, TokenKind::Symbol(Symbol::ClosingBracket))?;
+        // int a[2][3] is an array of 2 arrays of 3 ints.
+        let ty = type_suffix(pc, ty)?;
+        // Offsets within an object must fit in ptrdiff_t.
+        match size_of(&ty).checked_mul(len) {
+            Some(size) if size <= isize::MAX as usize => {}
+            _ => {
+                return Err(Diagnostic::error("array is too large")
+                    .with_primary(tok.loc(), "")
+                    .with_note(format!("the size of an array must not exceed {} bytes", isize::MAX)));
+            }
+        }
+        return Ok(array_of(ty, len));
+    }
+
     if pc.tokens[pc.pos].kind == TokenKind::Symbol(Symbol::OpeningParen) {
         pc.pos += 1;
 
         let mut params: Vec<Type> = vec![];
 
         while pc.tokens[pc.pos].kind != TokenKind::Symbol(Symbol::ClosingParen) {
-            if params.len() != 0 {
-                skip(pc, TokenKind::Symbol(Symbol::Comma));
+            if !params.is_empty() {
+                skip(pc, TokenKind::Symbol(Symbol::Comma))?;
+            }
+            let basety = typespec(pc)?;
+            let mut ty = declarator(pc, basety)?;
+            // An array parameter is a pointer to its first element.
+            if ty.kind == TypeKind::Array {
+                ty = Type { name: ty.name, ..pointer_to(*ty.base.unwrap()) };
             }
-            let basety = typespec(pc);
-            let ty = declarator(pc, basety);
             params.push(copy_type(ty));
         }
 
         ty = func_type(ty);
         ty.params = params;
 
-        skip(pc, TokenKind::Symbol(Symbol::ClosingParen));
-        return ty;
+        skip(pc, TokenKind::Symbol(Symbol::ClosingParen))?;
+        return Ok(ty);
     }
-    ty
+    Ok(ty)
 }
 
 // expr-stmt = expr ";"
-fn expr_stmt(pc: &mut ParseContext) -> Node {
-    let lhs = expr(pc);
-    let node = new_unary(NodeKind::ExprStmt, Box::new(lhs));
-    skip(pc, TokenKind::Symbol(Symbol::Semicolon));
-    node
+fn expr_stmt(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let start = span(pc);
+    let lhs = expr(pc)?;
+    let node = new_unary(NodeKind::ExprStmt, Box::new(lhs), &start);
+    skip(pc, TokenKind::Symbol(Symbol::Semicolon))?;
+    Ok(node)
+}
+
+// expr = assign ("," expr)?
+fn expr(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let node = assign(pc)?;
+    if pc.tokens[pc.pos].kind == TokenKind::Symbol(Symbol::Comma) {
+        let op = span(pc);
+        pc.pos += 1;
+        let rhs = expr(pc)?;
+        return Ok(new_binary(NodeKind::Comma, Box::new(node), Box::new(rhs), &op));
+    }
+    Ok(node)
 }
 
-// expr =  assign
-fn expr(pc: &mut ParseContext) -> Node {
-    assign(pc)
+// Convert `A op= B` to `tmp = &A, *tmp = *tmp op B`
+// so that A is evaluated only once.
+fn to_assign(pc: &mut ParseContext, kind: NodeKind, mut lhs: Node, rhs: Node, op: &Span) -> Result<Node, Diagnostic> {
+    check_assignable(&mut lhs, op)?;
+    lhs = add_type(&mut lhs);
+    let tmp = new_tmp_lvar(pc, pointer_to(lhs.ty.clone()));
+    let var = new_var_node(pc, VarRef::Local(tmp), op);
+    let deref = new_unary(NodeKind::Deref, Box::new(var.clone()), op);
+
+    let addr = new_unary(NodeKind::Addr, Box::new(lhs), op);
+    let expr1 = new_binary(NodeKind::Assign, Box::new(var), Box::new(addr), op);
+
+    let val = match kind {
+        NodeKind::Add => new_add(deref.clone(), rhs, op)?,
+        NodeKind::Sub => new_sub(deref.clone(), rhs, op)?,
+        _ => new_int_binary(kind, deref.clone(), rhs, op)?,
+    };
+    let expr2 = new_binary(NodeKind::Assign, Box::new(deref), Box::new(val), op);
+
+    Ok(new_binary(NodeKind::Comma, Box::new(expr1), Box::new(expr2), op))
+}
+
+// assign    = conditional (assign-op assign)?
+// assign-op = "=" | "+=" | "-=" | "*=" | "/=" | "%=" | "&=" | "|=" | "^="
+//           | "<<=" | ">>="
+fn assign(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = conditional(pc)?;
+    let kind = match pc.tokens[pc.pos].kind {
+        TokenKind::Symbol(Symbol::Assign)    => None,
+        TokenKind::Symbol(Symbol::AddAssign) => Some(NodeKind::Add),
+        TokenKind::Symbol(Symbol::SubAssign) => Some(NodeKind::Sub),
+        TokenKind::Symbol(Symbol::MulAssign) => Some(NodeKind::Mul),
+        TokenKind::Symbol(Symbol::DivAssign) => Some(NodeKind::Div),
+        TokenKind::Symbol(Symbol::ModAssign) => Some(NodeKind::Mod),
+        TokenKind::Symbol(Symbol::AndAssign) => Some(NodeKind::BitAnd),
+        TokenKind::Symbol(Symbol::OrAssign)  => Some(NodeKind::BitOr),
+        TokenKind::Symbol(Symbol::XorAssign) => Some(NodeKind::BitXor),
+        TokenKind::Symbol(Symbol::ShlAssign) => Some(NodeKind::Shl),
+        TokenKind::Symbol(Symbol::ShrAssign) => Some(NodeKind::Shr),
+        _ => return Ok(node)
+    };
+
+    let op = span(pc);
+    pc.pos += 1;
+    let rhs = assign(pc)?;
+    match kind {
+        Some(kind) => to_assign(pc, kind, node, rhs, &op),
+        None => {
+            check_assignable(&mut node, &op)?;
+            Ok(new_binary(NodeKind::Assign, Box::new(node), Box::new(rhs), &op))
+        }
+    }
+}
+
+// An array can't be the left-hand side of an assignment.
+fn check_assignable(lhs: &mut Node, op: &Span) -> Result<(), Diagnostic> {
+    let lhs = add_type(lhs);
+    if lhs.ty.kind != TypeKind::Array {
+        return Ok(());
+    }
+    let mut diag = Diagnostic::error("array type is not assignable").with_primary(op, "");
+    if let Some(span) = &lhs.span {
+        diag = diag.with_secondary(span, "this is an array");
+    }
+    Err(diag)
+}
+
+fn array_initializer(op: &Span) -> Diagnostic {
+    Diagnostic::error("array initializer is not supported")
+        .with_primary(op, "")
+        .with_help("assign to each element instead")
 }
 
-// assign = equality ("=" assign)?
-fn assign(pc: &mut ParseContext) -> Node {
-    let mut node = equality(pc);
-    if pc.tokens[pc.pos].kind == TokenKind::Symbol(Symbol::Assign) {
+// conditional = logor ("?" expr ":" conditional)?
+fn conditional(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let cond = logor(pc)?;
+    if pc.tokens[pc.pos].kind != TokenKind::Symbol(Symbol::Question) {
+        return Ok(cond);
+    }
+
+    let mut node = new_node(NodeKind::Cond, &span(pc));
+    pc.pos += 1;
+    node.cond = Some(Box::new(cond));
+    node.then = Some(Box::new(expr(pc)?));
+    skip(pc, TokenKind::Symbol(Symbol::Colon))?;
+    node.els = Some(Box::new(conditional(pc)?));
+    Ok(node)
+}
+
+// logor = logand ("||" logand)*
+fn logor(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = logand(pc)?;
+    while pc.tokens[pc.pos].kind == TokenKind::Symbol(Symbol::LogOr) {
+        let op = span(pc);
+        pc.pos += 1;
+        let rhs = logand(pc)?;
+        node = new_binary(NodeKind::LogOr, Box::new(node), Box::new(rhs), &op);
+    }
+    Ok(node)
+}
+
+// logand = bitor ("&&" bitor)*
+fn logand(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = bitor(pc)?;
+    while pc.tokens[pc.pos].kind == TokenKind::Symbol(Symbol::LogAnd) {
+        let op = span(pc);
         pc.pos += 1;
-        let rhs = assign(pc);
-        node = new_binary(NodeKind::Assign, Box::new(node), Box::new(rhs));  
+        let rhs = bitor(pc)?;
+        node = new_binary(NodeKind::LogAnd, Box::new(node), Box::new(rhs), &op);
     }
+    Ok(node)
+}
 
-    node
+// bitor = bitxor ("|" bitxor)*
+fn bitor(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = bitxor(pc)?;
+    while pc.tokens[pc.pos].kind == TokenKind::Symbol(Symbol::Pipe) {
+        let op = span(pc);
+        pc.pos += 1;
+        let rhs = bitxor(pc)?;
+        node = new_int_binary(NodeKind::BitOr, node, rhs, &op)?;
+    }
+    Ok(node)
+}
+
+// bitxor = bitand ("^" bitand)*
+fn bitxor(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = bitand(pc)?;
+    while pc.tokens[pc.pos].kind == TokenKind::Symbol(Symbol::Caret) {
+        let op = span(pc);
+        pc.pos += 1;
+        let rhs = bitand(pc)?;
+        node = new_int_binary(NodeKind::BitXor, node, rhs, &op)?;
+    }
+    Ok(node)
+}
+
+// bitand = equality ("&" equality)*
+fn bitand(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = equality(pc)?;
+    while pc.tokens[pc.pos].kind == TokenKind::Symbol(Symbol::Ampersand) {
+        let op = span(pc);
+        pc.pos += 1;
+        let rhs = equality(pc)?;
+        node = new_int_binary(NodeKind::BitAnd, node, rhs, &op)?;
+    }
+    Ok(node)
 }
 
 // equality = relational ("==" relational | "!=" relational)*
-fn equality(pc: &mut ParseContext) -> Node {
-    let mut node = relational(pc);
+fn equality(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = relational(pc)?;
 
     loop {
         match pc.tokens[pc.pos].kind  {
             TokenKind::Symbol(Symbol::Eq) => {
+                let op = span(pc);
                 pc.pos += 1;
-                let rhs = relational(pc);
-                node = new_binary(NodeKind::Equal, Box::new(node), Box::new(rhs));
+                let rhs = relational(pc)?;
+                node = new_binary(NodeKind::Equal, Box::new(node), Box::new(rhs), &op);
                 continue;
             }
             TokenKind::Symbol(Symbol::Ne) => {
+                let op = span(pc);
                 pc.pos += 1;
-                let rhs = relational(pc);
-                node = new_binary(NodeKind::Ne, Box::new(node), Box::new(rhs));
+                let rhs = relational(pc)?;
+                node = new_binary(NodeKind::Ne, Box::new(node), Box::new(rhs), &op);
                 continue;
             }
-            _ => return node
+            _ => return Ok(node)
         }
     }
 
 }
 
-// relational = add ("<" add | "<=" add | ">" add | ">=" add)*
-fn relational(pc: &mut ParseContext) -> Node {
-    let mut node = add(pc);
+// relational = shift ("<" shift | "<=" shift | ">" shift | ">=" shift)*
+fn relational(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = shift(pc)?;
 
     loop {
         match pc.tokens[pc.pos].kind {
             TokenKind::Symbol(Symbol::Lt) => {
+                let op = span(pc);
                 pc.pos += 1;
-                let rhs = add(pc);
-                node = new_binary(NodeKind::Lt, Box::new(node), Box::new(rhs));
+                let rhs = shift(pc)?;
+                node = new_binary(NodeKind::Lt, Box::new(node), Box::new(rhs), &op);
                 continue;
             }
             TokenKind::Symbol(Symbol::Le) => {
+                let op = span(pc);
                 pc.pos += 1;
-                let rhs = add(pc);
-                node = new_binary(NodeKind::Le, Box::new(node), Box::new(rhs));
+                let rhs = shift(pc)?;
+                node = new_binary(NodeKind::Le, Box::new(node), Box::new(rhs), &op);
                 continue;
             }
             TokenKind::Symbol(Symbol::Gt) => {
+                let op = span(pc);
                 pc.pos += 1;
-                let rhs = add(pc);
-                node = new_binary(NodeKind::Lt, Box::new(rhs), Box::new(node));
+                let rhs = shift(pc)?;
+                node = new_binary(NodeKind::Lt, Box::new(rhs), Box::new(node), &op);
                 continue;
             }
             TokenKind::Symbol(Symbol::Ge) => {
+                let op = span(pc);
                 pc.pos += 1;
-                let rhs = add(pc);
-                node = new_binary(NodeKind::Le, Box::new(rhs), Box::new(node));
+                let rhs = shift(pc)?;
+                node = new_binary(NodeKind::Le, Box::new(rhs), Box::new(node), &op);
                 continue;
             }
-            _ => return node
+            _ => return Ok(node)
+        }
+    }
+}
+
+// shift = add ("<<" add | ">>" add)*
+fn shift(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = add(pc)?;
+
+    loop {
+        let kind = match pc.tokens[pc.pos].kind {
+            TokenKind::Symbol(Symbol::Shl) => NodeKind::Shl,
+            TokenKind::Symbol(Symbol::Shr) => NodeKind::Shr,
+            _ => return Ok(node)
+        };
+        let op = span(pc);
+        pc.pos += 1;
+        let rhs = add(pc)?;
+        node = new_int_binary(kind, node, rhs, &op)?;
+    }
+}
+
+// What kind of value an operand is, for diagnostics
+fn describe(ty: &Type) -> &'static str {
+    match ty.kind {
+        TypeKind::Array => "an array",
+        _ if ty.base.is_some() => "a pointer",
+        _ => "an integer",
+    }
+}
+
+fn invalid_operands(lhs: &Node, rhs: &Node, op: &Span) -> Diagnostic {
+    let mut diag = Diagnostic::error(format!("invalid operands to binary '{}'", op.text()))
+        .with_primary(op, "");
+    for (node, side) in [(lhs, "left"), (rhs, "right")].iter() {
+        if let Some(span) = &node.span {
+            diag = diag.with_secondary(span, format!("{} operand is {}", side, describe(&node.ty)));
         }
     }
+    diag
 }
 
-fn new_add(mut lhs: Node, mut rhs: Node) -> Node {
+// Binary operator only defined on integers, e.g. "%" or "<<"
+fn new_int_binary(kind: NodeKind, mut lhs: Node, mut rhs: Node, op: &Span) -> Result<Node, Diagnostic> {
+    lhs = add_type(&mut lhs);
+    rhs = add_type(&mut rhs);
+    if !is_integer(&lhs.ty) || !is_integer(&rhs.ty) {
+        return Err(invalid_operands(&lhs, &rhs, op)
+            .with_note("both operands must be integers"));
+    }
+    Ok(new_binary(kind, Box::new(lhs), Box::new(rhs), op))
+}
+
+// `*expr`. Only pointers and arrays can be dereferenced.
+fn new_deref(mut expr: Node, op: &Span) -> Result<Node, Diagnostic> {
+    expr = add_type(&mut expr);
+    if expr.ty.base.is_none() {
+        let mut diag = Diagnostic::error("indirection requires a pointer operand")
+            .with_primary(op, "");
+        if let Some(span) = &expr.span {
+            diag = diag.with_secondary(span, format!("operand is {}", describe(&expr.ty)));
+        }
+        return Err(diag);
+    }
+    Ok(new_unary(NodeKind::Deref, Box::new(expr), op))
+}
+
+// Unary operator only defined on integers, e.g. "~"
+fn new_int_unary(kind: NodeKind, mut expr: Node, op: &Span) -> Result<Node, Diagnostic> {
+    expr = add_type(&mut expr);
+    if !is_integer(&expr.ty) {
+        let mut diag = Diagnostic::error(format!("invalid operand to unary '{}'", op.text()))
+            .with_primary(op, "");
+        if let Some(span) = &expr.span {
+            diag = diag.with_secondary(span, format!("operand is {}", describe(&expr.ty)));
+        }
+        return Err(diag.with_note("the operand must be an integer"));
+    }
+    Ok(new_unary(kind, Box::new(expr), op))
+}
+
+fn new_add(mut lhs: Node, mut rhs: Node, op: &Span) -> Result<Node, Diagnostic> {
     lhs = add_type(&mut lhs);
     rhs = add_type(&mut rhs);
 
     // num + num
     if is_integer(&lhs.ty) && is_integer(&rhs.ty) {
-        return new_binary(NodeKind::Add, Box::new(lhs.clone()), Box::new(rhs.clone()));
+        return Ok(new_binary(NodeKind::Add, Box::new(lhs), Box::new(rhs), op));
     }
 
-    if lhs.ty.base != None && rhs.ty.base != None {
-        eprintln!("invalid operands");
-        eprintln!("lhs = {:#?}", lhs);
-        eprintln!("rhs = {:#?}", rhs);
+    if lhs.ty.base.is_some() && rhs.ty.base.is_some() {
+        return Err(invalid_operands(&lhs, &rhs, op)
+            .with_note("two pointers cannot be added"));
     }
 
     // Canonicalize `num + ptr` to `ptr + num`.
-    if lhs.ty.base == None && rhs.ty.base != None {
-        let tmp = lhs;
-        lhs = rhs;
-        rhs = tmp;
+    if lhs.ty.base.is_none() && rhs.ty.base.is_some() {
+        std::mem::swap(&mut lhs, &mut rhs);
     }
 
     // ptr + num
-    rhs = new_binary(NodeKind::Mul, Box::new(rhs), Box::new(get_number(8)));
-    new_binary(NodeKind::Add, Box::new(lhs), Box::new(rhs))
+    let size = size_of(lhs.ty.base.as_ref().unwrap()) as i64;
+    rhs = new_binary(NodeKind::Mul, Box::new(rhs), Box::new(get_number(size, op)), op);
+    Ok(new_binary(NodeKind::Add, Box::new(lhs), Box::new(rhs), op))
 }
 
-fn new_sub(mut lhs: Node, mut rhs: Node) -> Node {
+fn new_sub(mut lhs: Node, mut rhs: Node, op: &Span) -> Result<Node, Diagnostic> {
     lhs = add_type(&mut lhs);
     rhs = add_type(&mut rhs);
 
     // num - num
     if is_integer(&lhs.ty) && is_integer(&rhs.ty) {
-        return new_binary(NodeKind::Sub, Box::new(lhs.clone()), Box::new(rhs.clone()));
+        return Ok(new_binary(NodeKind::Sub, Box::new(lhs), Box::new(rhs), op));
     }
 
     // ptr - num
-    if lhs.ty.base != None && is_integer(&rhs.ty) {
-        rhs = new_binary(NodeKind::Mul, Box::new(rhs), Box::new(get_number(8)));
-        return new_binary(NodeKind::Sub, Box::new(lhs), Box::new(rhs));
+    if let (Some(base), true) = (&lhs.ty.base, is_integer(&rhs.ty)) {
+        let size = size_of(base) as i64;
+        rhs = new_binary(NodeKind::Mul, Box::new(rhs), Box::new(get_number(size, op)), op);
+        return Ok(new_binary(NodeKind::Sub, Box::new(lhs), Box::new(rhs), op));
     }
 
     // num - ptr (error)
-    if lhs.ty.base == None && rhs.ty.base != None {
-        eprintln!("invalid operands");
-        eprintln!("lhs = {:#?}", lhs);
-        eprintln!("rhs = {:#?}", rhs);
+    if lhs.ty.base.is_none() && rhs.ty.base.is_some() {
+        return Err(invalid_operands(&lhs, &rhs, op)
+            .with_help("a pointer can only be subtracted from another pointer"));
     }
 
     // `ptr-ptr` returns the result of `ptr-ptr` divided by its size.
     // The result is a number of elements, but the value can also be negative.
-    lhs = new_binary(NodeKind::Sub, Box::new(lhs), Box::new(rhs));
-    new_binary(NodeKind::Div, Box::new(lhs), Box::new(get_number(8)))
+    let (base, rhs_base) = (lhs.ty.base.as_ref().unwrap(), rhs.ty.base.as_ref().unwrap());
+    if !is_compatible(base, rhs_base) {
+        return Err(invalid_operands(&lhs, &rhs, op)
+            .with_note("the pointers point to different types"));
+    }
+    let size = size_of(base) as i64;
+    lhs = new_binary(NodeKind::Sub, Box::new(lhs), Box::new(rhs), op);
+    Ok(new_binary(NodeKind::Div, Box::new(lhs), Box::new(get_number(size, op)), op))
 }
 
 // add = mul ("+" mul | "-" mul)*
-fn add(pc: &mut ParseContext) -> Node {
-    let mut node = mul(pc);
+fn add(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = mul(pc)?;
 
     loop {
         match pc.tokens[pc.pos].kind {
             TokenKind::Symbol(Symbol::Add) => {
+                let op = span(pc);
                 pc.pos += 1;
-                let rhs = mul(pc);
-                node = new_add(node, rhs);
+                let rhs = mul(pc)?;
+                node = new_add(node, rhs, &op)?;
                 continue;
             }
             TokenKind::Symbol(Symbol::Sub) => {
+                let op = span(pc);
                 pc.pos += 1;
-                let rhs = mul(pc);
-                node = new_sub(node, rhs);
+                let rhs = mul(pc)?;
+                node = new_sub(node, rhs, &op)?;
                 continue;
             }
-            _ => return node
+            _ => return Ok(node)
         }
     }
 }
 
-// mul = unary ("*" unary | "/" unary)*
-fn mul(pc: &mut ParseContext) -> Node {
-    let mut node = unary(pc);
+// mul = unary ("*" unary | "/" unary | "%" unary)*
+fn mul(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = unary(pc)?;
 
     loop {
         match pc.tokens[pc.pos].kind {
             TokenKind::Symbol(Symbol::Asterisk) => {
+                let op = span(pc);
                 pc.pos += 1;
-                let rhs = unary(pc);
-                node = new_binary(NodeKind::Mul, Box::new(node), Box::new(rhs));
+                let rhs = unary(pc)?;
+                node = new_int_binary(NodeKind::Mul, node, rhs, &op)?;
                 continue;
             }
             TokenKind::Symbol(Symbol::Div) => {
+                let op = span(pc);
+                pc.pos += 1;
+                let rhs = unary(pc)?;
+                node = new_int_binary(NodeKind::Div, node, rhs, &op)?;
+                continue;
+            }
+            TokenKind::Symbol(Symbol::Percent) => {
+                let op = span(pc);
                 pc.pos += 1;
-                let rhs = unary(pc);
-                node = new_binary(NodeKind::Div, Box::new(node), Box::new(rhs));
+                let rhs = unary(pc)?;
+                node = new_int_binary(NodeKind::Mod, node, rhs, &op)?;
                 continue;
             }
-            _ => return node
+            _ => return Ok(node)
         }
 
     }
 }
 
-// unary = ("+" | "-" | "&" | "*")? unary
-//       | primary
-fn unary(pc: &mut ParseContext) -> Node {
+// unary = ("+" | "-" | "&" | "*" | "!" | "~")? unary
+//       | ("++" | "--") unary
+//       | postfix
+fn unary(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let op = span(pc);
     match pc.tokens[pc.pos].kind {
         TokenKind::Symbol(Symbol::Add) => {
             pc.pos += 1;
-            return unary(pc);
+            unary(pc)
         }
         TokenKind::Symbol(Symbol::Sub) => {
             pc.pos += 1;
-            let node = unary(pc);
-            return new_binary(NodeKind::Sub, Box::new(get_number(0)), Box::new(node));
+            let node = unary(pc)?;
+            Ok(new_binary(NodeKind::Sub, Box::new(get_number(0, &op)), Box::new(node), &op))
         }
         TokenKind::Symbol(Symbol::Ampersand) => {
             pc.pos += 1;
-            let node = unary(pc);
-            return new_unary(NodeKind::Addr, Box::new(node));
+            let node = unary(pc)?;
+            Ok(new_unary(NodeKind::Addr, Box::new(node), &op))
         }
         TokenKind::Symbol(Symbol::Asterisk) => {
             pc.pos += 1;
-            let node = unary(pc);
-            return new_unary(NodeKind::Deref, Box::new(node));
+            let node = unary(pc)?;
+            new_deref(node, &op)
+        }
+        TokenKind::Symbol(Symbol::Not) => {
+            pc.pos += 1;
+            let node = unary(pc)?;
+            Ok(new_unary(NodeKind::Not, Box::new(node), &op))
         }
-        _ => return primary(pc)
+        TokenKind::Symbol(Symbol::Tilde) => {
+            pc.pos += 1;
+            let node = unary(pc)?;
+            new_int_unary(NodeKind::BitNot, node, &op)
+        }
+        // `++i` is `i += 1`
+        TokenKind::Symbol(Symbol::Inc) => {
+            pc.pos += 1;
+            let node = unary(pc)?;
+            to_assign(pc, NodeKind::Add, node, get_number(1, &op), &op)
+        }
+        // `--i` is `i -= 1`
+        TokenKind::Symbol(Symbol::Dec) => {
+            pc.pos += 1;
+            let node = unary(pc)?;
+            to_assign(pc, NodeKind::Sub, node, get_number(1, &op), &op)
+        }
+        _ => postfix(pc)
+    }
+}
+
+// postfix = primary ("[" expr "]" | "++" | "--")*
+fn postfix(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
+    let mut node = primary(pc)?;
+
+    loop {
+        let op = span(pc);
+        match pc.tokens[pc.pos].kind {
+            // `a[i]` is `*(a + i)`
+            TokenKind::Symbol(Symbol::OpeningBracket) => {
+                pc.pos += 1;
+                let idx = expr(pc)?;
+                skip(pc, TokenKind::Symbol(Symbol::ClosingBracket))?;
+                let addr = new_add(node, idx, &op)?;
+                node = new_deref(addr, &op)?;
+            }
+            TokenKind::Symbol(Symbol::Inc) => {
+                pc.pos += 1;
+                node = new_inc_dec(pc, NodeKind::Add, node, &op)?;
+            }
+            TokenKind::Symbol(Symbol::Dec) => {
+                pc.pos += 1;
+                node = new_inc_dec(pc, NodeKind::Sub, node, &op)?;
+            }
+            _ => return Ok(node)
+        }
+    }
+}
+
+// Convert `A++` to `tmp = &A, old = *tmp, *tmp = *tmp + 1, old`.
+// The old value is kept in a variable of A's type, so it is not
+// affected by the wrap-around of narrow types.
+fn new_inc_dec(pc: &mut ParseContext, kind: NodeKind, mut lhs: Node, op: &Span) -> Result<Node, Diagnostic> {
+    check_assignable(&mut lhs, op)?;
+    lhs = add_type(&mut lhs);
+    let ty = Type { name: None, ..lhs.ty.clone() };
+    let tmp = new_tmp_lvar(pc, pointer_to(ty.clone()));
+    let var = new_var_node(pc, VarRef::Local(tmp), op);
+    let deref = new_unary(NodeKind::Deref, Box::new(var.clone()), op);
+    let old = new_tmp_lvar(pc, ty);
+    let old = new_var_node(pc, VarRef::Local(old), op);
+
+    let addr = new_unary(NodeKind::Addr, Box::new(lhs), op);
+    let expr1 = new_binary(NodeKind::Assign, Box::new(var), Box::new(addr), op);
+    let expr2 = new_binary(NodeKind::Assign, Box::new(old.clone()), Box::new(deref.clone()), op);
+    let val = match kind {
+        NodeKind::Add => new_add(deref.clone(), get_number(1, op), op)?,
+        _ => new_sub(deref.clone(), get_number(1, op), op)?,
     };
+    let expr3 = new_binary(NodeKind::Assign, Box::new(deref), Box::new(val), op);
+
+    let node = new_binary(NodeKind::Comma, Box::new(expr1), Box::new(expr2), op);
+    let node = new_binary(NodeKind::Comma, Box::new(node), Box::new(expr3), op);
+    Ok(new_binary(NodeKind::Comma, Box::new(node), Box::new(old), op))
 }
 
-// primary   = "(" expr ")" | ident func-args? | num
-fn primary(pc: &mut ParseContext) -> Node {
+// Create an anonymous global variable holding a string literal.
+// Adjacent string literals are concatenated.
+fn new_string_literal(pc: &mut ParseContext) -> Node {
+    let start = span(pc);
+    let mut data = vec![];
+    while let TokenKind::Str(s) = &pc.tokens[pc.pos].kind {
+        data.extend_from_slice(s);
+        pc.pos += 1;
+    }
+    data.push(0);
+
+    let var = Var {
+        name: pc.names.intern(&format!(".L.str.{}", pc.globals.len())),
+        ty: array_of(ty_char(), data.len()),
+        init_data: Some(data),
+        is_readonly: true,
+        ..Default::default()
+    };
+    pc.globals.push(var);
+
+    // A string literal is an array, which decays to its address.
+    new_var_node(pc, VarRef::Global(pc.globals.len()-1), &start)
+}
+
+// primary   = "(" expr ")" | ident func-args? | str | num
+fn primary(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
     if pc.tokens[pc.pos].kind == TokenKind::Symbol(Symbol::OpeningParen) {
         pc.pos += 1;
-        let node = expr(pc);
-        skip(pc, TokenKind::Symbol(Symbol::ClosingParen));
-        return node;
+        let node = expr(pc)?;
+        skip(pc, TokenKind::Symbol(Symbol::ClosingParen))?;
+        return Ok(node);
     }
 
     if pc.tokens[pc.pos].kind.is_identifier() {
@@ -599,22 +1358,30 @@ fn primary(pc: &mut ParseContext) -> Node {
         }
 
         // Variable
-        let var = find_var(pc);
-        if var == None {
-            eprintln!("undefined variable: {}", pc.tokens[pc.pos].get_string());
-            exit(1);
-        }
+        let var = match find_var(pc) {
+            Some(var) => var,
+            None => {
+                let tok = &pc.tokens[pc.pos];
+                return Err(Diagnostic::error(format!("undefined variable '{}'", pc.names.get(tok.get_ident())))
+                    .with_primary(tok.loc(), "not found in this scope"));
+            }
+        };
+        let node = new_var_node(pc, var, &span(pc));
         pc.pos += 1;
-        return new_var_node(var.unwrap());
+        return Ok(node);
+    }
+
+    if pc.tokens[pc.pos].kind.is_str() {
+        return Ok(new_string_literal(pc));
     }
 
-    let node = new_num(pc);
+    let node = new_num(pc)?;
     pc.pos += 1;
-    node
+    Ok(node)
 }
 
 // func-args = "(" (assign ("," assign)*)? ")"
-fn funcall(pc: &mut ParseContext) -> Node {
+fn funcall(pc: &mut ParseContext) -> Result<Node, Diagnostic> {
     let start = pc.pos;
     pc.pos += 2;   // eat ident & "("
 
@@ -622,29 +1389,104 @@ fn funcall(pc: &mut ParseContext) -> Node {
 
     while pc.tokens[pc.pos].kind != TokenKind::Symbol(Symbol::ClosingParen) {
         if (pc.pos-2) != start {
-            skip(pc, TokenKind::Symbol(Symbol::Comma));
+            skip(pc, TokenKind::Symbol(Symbol::Comma))?;
         }
-        let mut node = assign(pc);
+        let mut node = assign(pc)?;
         args.push(Box::new(add_type(&mut node)));
     }
 
-    skip(pc, TokenKind::Symbol(Symbol::ClosingParen));
-
-    let node = Node {
-        kind: NodeKind::Funcall,
-        funcname: pc.tokens[start].get_string(),
-        args: Some(args),
-        ..Default::default()
+    skip(pc, TokenKind::Symbol(Symbol::ClosingParen))?;
+
+    // An undeclared function implicitly returns int. Variables, including
+    // locals that shadow a function, can't be called.
+    let funcname = pc.tokens[start].get_ident();
+    let callee = pc.scopes.iter().rev()
+        .find_map(|scope| scope.get(&funcname))
+        .map(|&i| &pc.locals[i])
+        .or_else(|| pc.global_names.get(&funcname).map(|&i| &pc.globals[i]));
+    let ty = match callee {
+        Some(Var { ty: Type { kind: TypeKind::Func, return_ty: Some(return_ty), .. }, .. }) => *return_ty.clone(),
+        Some(var) => {
+            let mut diag = Diagnostic::error(format!("called object '{}' is not a function", pc.names.get(funcname)))
+                .with_primary(pc.tokens[start].loc(), "");
+            if let Some(span) = &var.span {
+                diag = diag.with_secondary(span, "declared here");
+            }
+            return Err(diag);
+        }
+        None => ty_int(),
     };
+    Ok(Node {
+        funcname,
+        args: Some(args),
+        ty,
+        ..new_node(NodeKind::Funcall, pc.tokens[start].loc())
+    })
+}
 
-    node
+fn skip(pc: &mut ParseContext, t: TokenKind) -> Result<(), Diagnostic> {
+    let tok = &pc.tokens[pc.pos];
+    if tok.kind != t {
+        return Err(Diagnostic::error(format!("expected {}, found {}", t, tok))
+            .with_primary(tok.loc(), format!("expected {}", t)));
+    }
+    pc.pos += 1;
+    Ok(())
 }
 
-fn skip(pc: &mut ParseContext, t: TokenKind){
-    if pc.tokens[pc.pos].kind != t {
-        panic!("expected '{}'", pc.tokens[pc.pos].get_string());
+// Skip tokens after a syntax error until the end of the broken
+// statement: past a ";" or a nested "{...}", or up to the "}" closing
+// the enclosing block.
+fn synchronize(pc: &mut ParseContext) {
+    let mut depth = 0;
+    loop {
+        match pc.tokens[pc.pos].kind {
+            TokenKind::Eof => return,
+            TokenKind::Symbol(Symbol::Semicolon) if depth == 0 => {
+                pc.pos += 1;
+                return;
+            }
+            TokenKind::Symbol(Symbol::OpeningBrace) => depth += 1,
+            TokenKind::Symbol(Symbol::ClosingBrace) => {
+                if depth == 0 {
+                    return;
+                }
+                depth -= 1;
+                if depth == 0 {
+                    pc.pos += 1;
+                    return;
+                }
+            }
+            _ => {}
+        }
+        pc.pos += 1;
+    }
+}
+
+// Skip tokens after a syntax error at the top level until the next
+// function definition can start.
+fn synchronize_toplevel(pc: &mut ParseContext) {
+    let mut depth = 0;
+    loop {
+        match pc.tokens[pc.pos].kind {
+            TokenKind::Eof => return,
+            TokenKind::Symbol(Symbol::Semicolon) if depth == 0 => {
+                pc.pos += 1;
+                return;
+            }
+            TokenKind::Symbol(Symbol::OpeningBrace) => depth += 1,
+            TokenKind::Symbol(Symbol::ClosingBrace) => {
+                pc.pos += 1;
+                if depth <= 1 {
+                    return;
+                }
+                depth -= 1;
+                continue;
+            }
+            _ => {}
+        }
+        pc.pos += 1;
     }
-    pc.pos += 1
 }
 
 // トークンが期待するトークンの場合、トークンを一つ消費して
@@ -659,7 +1501,7 @@ fn consume(pc: &mut ParseContext, t: TokenKind) -> bool {
 
 
 #[derive(Debug, Default, Clone)] pub struct Function {
-    pub name: String,
+    pub name: Name,
     pub node: Node,
     pub locals: Vec<Var>,
     pub params: Vec<Var>,
@@ -669,22 +1511,49 @@ fn consume(pc: &mut ParseContext, t: TokenKind) -> bool {
 #[derive(Debug, Default)]
 pub struct Program {
     pub functions: Vec<Function>,
+    pub globals: Vec<Var>,
 }
 
-#[derive(Debug, Default)]
-struct ParseContext {
+#[derive(Debug)]
+struct ParseContext<'a> {
     tokens: Vec<Token>,
+    names: &'a mut Interner,
     pos: usize,
     locals: Vec<Var>,
+    scopes: Vec<HashMap<Name, usize>>,    // Index of locals by name, innermost last
+    globals: Vec<Var>,
+    global_names: HashMap<Name, usize>,   // Index of globals and functions by name
+    errors: Vec<Diagnostic>,    // Errors recovered from so far
 }
 
-// program = funcdef*
-pub fn parse(tokens: Vec<Token>) -> Program {
+// program = (funcdef | global-var)*
+pub fn parse(tokens: Vec<Token>, names: &mut Interner) -> Result<Program, Error> {
     let mut prog = Program { ..Default::default() };
-    let mut pc = ParseContext { tokens, ..Default::default() };
+    let mut pc = ParseContext {
+        tokens,
+        names,
+        pos: 0,
+        locals: vec![],
+        scopes: vec![],
+        globals: vec![],
+        global_names: HashMap::new(),
+        errors: vec![],
+    };
     while pc.tokens[pc.pos].kind != TokenKind::Eof {
-        let func = funcdef(&mut pc);
-        prog.functions.push(func);
+        let res = if is_function(&mut pc) {
+            funcdef(&mut pc).map(|func| prog.functions.extend(func))
+        } else {
+            global_variable(&mut pc)
+        };
+        if let Err(diag) = res {
+            pc.errors.push(diag);
+            synchronize_toplevel(&mut pc);
+        }
+    }
+
+    if !pc.errors.is_empty() {
+        return Err(Error { diagnostics: pc.errors });
     }
-    prog
+    prog.globals = pc.globals;
+    Ok(prog)
 }
@@ -0,0 +1,1347 @@
+use super::tokenize::{ Token, TokenKind, Symbol, Lexer, SourceFile, Span, IntType };
+use super::diagnostic::{ Diagnostic, Error };
+use super::intern::Interner;
+use std::collections::{ HashMap, HashSet };
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::rc::Rc;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+// Directories searched for #include after the -I directories
+const SYSTEM_INCLUDE_PATHS: [&str; 3] = [
+    "/usr/local/include",
+    "/usr/include/x86_64-linux-gnu",
+    "/usr/include",
+];
+
+// Macros expanded by the preprocessor itself
+const BUILTIN_MACROS: [&str; 3] = ["__FILE__", "__LINE__", "__has_include"];
+
+// gcc also gives up at this depth, which catches recursive includes.
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+struct Macro {
+    params: Option<Vec<String>>,    // None for object-like macros
+    is_variadic: bool,              // Takes "..." as the last parameter
+    body: Vec<Token>,
+}
+
+#[derive(PartialEq)]
+enum CondCtx {
+    Then,
+    Elif,
+    Else,
+}
+
+// An #if, #ifdef or #ifndef being processed
+struct CondIncl {
+    ctx: CondCtx,
+    tok: Token,         // The directive name, for diagnostics
+    included: bool,     // Whether a group has already been included
+}
+
+// -D and -U options
+#[derive(Debug, Clone)]
+pub enum MacroOption {
+    Define(String, String),     // -DNAME=value
+    Undef(String),              // -UNAME
+}
+
+// Preprocessor settings given on the command line
+#[derive(Debug, Default)]
+pub struct PreprocessOptions {
+    pub include_paths: Vec<PathBuf>,    // -I
+    pub macros: Vec<MacroOption>,       // In the order given
+}
+
+struct PreprocessContext<'a> {
+    tokens: Vec<Token>,     // Remaining input in reverse order
+    names: &'a mut Interner,
+    macros: HashMap<String, Rc<Macro>>,
+    errors: Vec<Diagnostic>,
+    warnings: Vec<Diagnostic>,  // Returned to the caller, who prints them
+
+    include_paths: Vec<PathBuf>,        // -I directories, then system directories
+    pragma_once: HashSet<PathBuf>,      // Files with "#pragma once"
+    cond_incl: Vec<CondIncl>,
+}
+
+fn error(tok: &Token, msg: &str) -> Diagnostic {
+    Diagnostic::error(msg).with_primary(tok.loc(), "")
+}
+
+// 次のトークンを取り出す。Eofは取り出さずに返し続ける
+fn next(pp: &mut PreprocessContext) -> Token {
+    if pp.tokens.len() == 1 {
+        return pp.tokens[0].clone();
+    }
+    pp.tokens.pop().unwrap()
+}
+
+fn peek<'a>(pp: &'a PreprocessContext<'_>) -> &'a Token {
+    pp.tokens.last().unwrap()
+}
+
+// Push tokens back so that they are read next.
+fn unget(pp: &mut PreprocessContext, tokens: Vec<Token>) {
+    pp.tokens.extend(tokens.into_iter().rev());
+}
+
+// Name of an identifier or keyword token, which can both be macro names
+fn ident_name(pp: &PreprocessContext, tok: &Token) -> Option<Rc<str>> {
+    match &tok.kind {
+        TokenKind::Ident(name) => Some(Rc::from(pp.names.get(*name))),
+        TokenKind::Keyword(k) => Some(Rc::from(k.as_str())),
+        _ => None,
+    }
+}
+
+fn is_symbol(tok: &Token, sym: Symbol) -> bool {
+    tok.kind == TokenKind::Symbol(sym)
+}
+
+// A "#" at the beginning of a line starts a directive. Tokens produced by
+// macro expansion never do.
+fn is_hash(tok: &Token) -> bool {
+    tok.at_bol && tok.hideset.is_empty() && is_symbol(tok, Symbol::Hash)
+}
+
+// Read the remaining tokens of the current line
+fn read_line(pp: &mut PreprocessContext) -> Vec<Token> {
+    let mut line = vec![];
+    while !peek(pp).at_bol && peek(pp).kind != TokenKind::Eof {
+        line.push(next(pp));
+    }
+    line
+}
+
+// Tokenize text created by the preprocessor, e.g. by "#" or "##".
+fn tokenize_scratch(names: &mut Interner, text: &str) -> Option<Vec<Token>> {
+    let file = SourceFile::scratch(text.to_string());
+    let mut tokens = Lexer::new(file, names).tokenize().ok()?;
+    tokens.pop();
+    if tokens.iter().any(|t| matches!(t.kind, TokenKind::Invalid(_))) {
+        return None;
+    }
+    Some(tokens)
+}
+
+fn add_hideset(tokens: &mut [Token], names: &[String]) {
+    for tok in tokens {
+        for name in names {
+            if !tok.hideset.contains(name) {
+                tok.hideset.push(name.clone());
+            }
+        }
+    }
+}
+
+// define-directive = ident ("(" params? ")")? replacement-list
+// params           = (ident ("," ident)* ("," "...")? | "...")
+fn read_define(pp: &mut PreprocessContext, hash: &Token) -> Result<(), Diagnostic> {
+    let mut line = read_line(pp).into_iter().peekable();
+    let name_tok = match line.next() {
+        Some(tok) => tok,
+        None => return Err(error(hash, "macro name missing")),
+    };
+    let name = match ident_name(pp, &name_tok) {
+        Some(name) => name.to_string(),
+        None => return Err(error(&name_tok, "macro name must be an identifier")),
+    };
+
+    let mut params = None;
+    let mut is_variadic = false;
+
+    // Function-like macro: "(" immediately follows the name.
+    if line.peek().is_some_and(|t| is_symbol(t, Symbol::OpeningParen) && !t.has_space) {
+        let open = line.next().unwrap();
+        let mut names: Vec<String> = vec![];
+        loop {
+            let tok = match line.next() {
+                Some(tok) => tok,
+                None => {
+                    return Err(Diagnostic::error("missing ')' in macro parameter list")
+                        .with_primary(&open.span, "parameter list starts here"));
+                }
+            };
+            if names.is_empty() && is_symbol(&tok, Symbol::ClosingParen) {
+                break;
+            }
+            if is_symbol(&tok, Symbol::Ellipsis) {
+                is_variadic = true;
+                match line.next() {
+                    Some(t) if is_symbol(&t, Symbol::ClosingParen) => break,
+                    Some(t) => return Err(error(&t, "expected ')' after '...'")),
+                    None => return Err(error(&tok, "expected ')' after '...'")),
+                }
+            }
+            let param = match ident_name(pp, &tok) {
+                Some(param) => param.to_string(),
+                None => return Err(error(&tok, "expected a parameter name")),
+            };
+            if names.contains(&param) {
+                return Err(error(&tok, &format!("duplicate macro parameter '{}'", param)));
+            }
+            names.push(param);
+
+            match line.next() {
+                Some(t) if is_symbol(&t, Symbol::ClosingParen) => break,
+                Some(t) if is_symbol(&t, Symbol::Comma) => continue,
+                Some(t) => return Err(error(&t, "expected ',' or ')' in macro parameter list")),
+                None => return Err(error(&tok, "expected ',' or ')' in macro parameter list")),
+            }
+        }
+        if is_variadic {
+            names.push("__VA_ARGS__".to_string());
+        }
+        params = Some(names);
+    }
+
+    let body: Vec<Token> = line.collect();
+
+    // "##" needs an operand on both sides, and "#" must be followed by a parameter.
+    for (i, tok) in body.iter().enumerate() {
+        if is_symbol(tok, Symbol::HashHash) && (i == 0 || i == body.len()-1) {
+            return Err(error(tok, "'##' cannot appear at either end of a macro expansion"));
+        }
+        if let Some(params) = &params {
+            if is_symbol(tok, Symbol::Hash) {
+                let is_param = body.get(i+1)
+                    .and_then(|t| ident_name(pp, t))
+                    .is_some_and(|n| params.iter().any(|p| **p == *n));
+                if !is_param {
+                    return Err(error(tok, "'#' is not followed by a macro parameter"));
+                }
+            }
+        }
+    }
+
+    pp.macros.insert(name, Rc::new(Macro { params, is_variadic, body }));
+    Ok(())
+}
+
+// Read the arguments of a function-like macro call. The name and "(" have
+// been consumed. Returns the arguments and the closing ")".
+fn read_macro_args(pp: &mut PreprocessContext, name: &Token, m: &Macro)
+                   -> Result<(Vec<Vec<Token>>, Token), Diagnostic> {
+    let params = m.params.as_ref().unwrap();
+    let mut args = vec![vec![]];
+    let mut depth = 0;
+
+    let rparen = loop {
+        let tok = next(pp);
+        match tok.kind {
+            TokenKind::Eof => {
+                return Err(error(name, &format!(
+                    "unterminated argument list invoking macro '{}'", name.span.text())));
+            }
+            TokenKind::Symbol(Symbol::OpeningParen) => depth += 1,
+            TokenKind::Symbol(Symbol::ClosingParen) if depth == 0 => break tok,
+            TokenKind::Symbol(Symbol::ClosingParen) => depth -= 1,
+            // Commas in the variadic part belong to __VA_ARGS__.
+            TokenKind::Symbol(Symbol::Comma) if depth == 0
+                && !(m.is_variadic && args.len() == params.len()) => {
+                args.push(vec![]);
+                continue;
+            }
+            _ => {}
+        }
+        args.last_mut().unwrap().push(tok);
+    };
+
+    // "()" is no arguments rather than one empty argument for "#define F()".
+    if params.is_empty() && args.len() == 1 && args[0].is_empty() {
+        args.clear();
+    }
+    // __VA_ARGS__ may be omitted.
+    if m.is_variadic && args.len() == params.len() - 1 {
+        args.push(vec![]);
+    }
+
+    let name = name.span.text();
+    if args.len() < params.len() {
+        return Err(error(&rparen, &format!("macro '{}' requires {} arguments, but only {} given",
+                                           name, params.len(), args.len())));
+    }
+    if args.len() > params.len() {
+        return Err(error(&rparen, &format!("macro '{}' passed {} arguments, but takes just {}",
+                                           name, args.len(), params.len())));
+    }
+    Ok((args, rparen))
+}
+
+// Spell tokens as they appear in the source, separated by single spaces.
+fn join_tokens(tokens: &[Token]) -> String {
+    let mut buf = String::new();
+    for (i, tok) in tokens.iter().enumerate() {
+        if i > 0 && (tok.has_space || tok.at_bol) {
+            buf.push(' ');
+        }
+        buf.push_str(&tok.span.text());
+    }
+    buf
+}
+
+// Turn `#x` into a string literal of the spelling of the argument.
+fn stringize(names: &mut Interner, hash: &Token, arg: &[Token]) -> Token {
+    let text = join_tokens(arg).replace('\\', "\\\\").replace('"', "\\\"");
+    let mut tok = tokenize_scratch(names, &format!("\"{}\"", text)).unwrap().remove(0);
+    tok.has_space = hash.has_space;
+    tok
+}
+
+// Concatenate two tokens for "##". The result must be a single token.
+fn paste(names: &mut Interner, lhs: &Token, rhs: &Token, op: &Token) -> Result<Token, Diagnostic> {
+    let text = format!("{}{}", lhs.span.text(), rhs.span.text());
+    match tokenize_scratch(names, &text) {
+        Some(mut tokens) if tokens.len() == 1 => {
+            let mut tok = tokens.remove(0);
+            tok.has_space = lhs.has_space;
+            Ok(tok)
+        }
+        _ => Err(Diagnostic::error(format!(
+                "pasting \"{}\" and \"{}\" does not give a valid preprocessing token",
+                lhs.span.text(), rhs.span.text()))
+            .with_primary(&op.span, "")),
+    }
+}
+
+// Fully macro-expand an argument on its own.
+fn expand_arg(pp: &mut PreprocessContext, arg: &[Token]) -> Result<Vec<Token>, Diagnostic> {
+    let eof = pp.tokens[0].clone();
+    let saved = std::mem::replace(&mut pp.tokens, vec![eof]);
+    unget(pp, arg.to_vec());
+
+    let mut out = vec![];
+    let result = loop {
+        let tok = next(pp);
+        if tok.kind == TokenKind::Eof {
+            break Ok(out);
+        }
+        match expand_macro(pp, &tok) {
+            Ok(true) => {}
+            Ok(false) => out.push(tok),
+            Err(diag) => break Err(diag),
+        }
+    };
+
+    pp.tokens = saved;
+    result
+}
+
+// Argument for tok if it is a parameter of m
+fn find_arg<'a>(pp: &PreprocessContext, m: &Macro, args: &'a [Vec<Token>], tok: Option<&Token>)
+                -> Option<&'a Vec<Token>> {
+    let name = ident_name(pp, tok?)?;
+    let i = m.params.as_ref()?.iter().position(|p| **p == *name)?;
+    Some(&args[i])
+}
+
+// Replace the parameters in a macro body with the arguments.
+fn subst(pp: &mut PreprocessContext, m: &Macro, args: &[Vec<Token>]) -> Result<Vec<Token>, Diagnostic> {
+
+    let body = &m.body;
+    let mut out: Vec<Token> = vec![];
+    let mut i = 0;
+    while i < body.len() {
+        let tok = &body[i];
+
+        // "#" followed by a parameter
+        if m.params.is_some() && is_symbol(tok, Symbol::Hash) {
+            let arg = find_arg(pp, m, args, body.get(i+1)).unwrap();
+            out.push(stringize(pp.names, tok, arg));
+            i += 2;
+            continue;
+        }
+
+        // lhs ## rhs
+        if is_symbol(tok, Symbol::HashHash) {
+            let rhs = &body[i+1];
+
+            // [GNU] ", ## __VA_ARGS__" drops the comma if __VA_ARGS__ is empty.
+            if m.is_variadic && ident_name(pp, rhs).as_deref() == Some("__VA_ARGS__")
+                && out.last().is_some_and(|t| is_symbol(t, Symbol::Comma)) {
+                let arg = find_arg(pp, m, args, Some(rhs)).unwrap();
+                if arg.is_empty() {
+                    out.pop();
+                } else {
+                    out.extend(arg.iter().cloned());
+                }
+                i += 2;
+                continue;
+            }
+
+            let rhs = match find_arg(pp, m, args, Some(rhs)) {
+                Some(arg) => arg.clone(),
+                None => vec![rhs.clone()],
+            };
+            if let Some(first) = rhs.first() {
+                match out.pop() {
+                    Some(lhs) => out.push(paste(pp.names, &lhs, first, tok)?),
+                    None => out.push(first.clone()),
+                }
+                out.extend(rhs[1..].iter().cloned());
+            }
+            i += 2;
+            continue;
+        }
+
+        if let Some(arg) = find_arg(pp, m, args, Some(tok)) {
+            let pasted = body.get(i+1).is_some_and(|t| is_symbol(t, Symbol::HashHash));
+
+            // An empty argument before "##" leaves the right-hand side as is.
+            if pasted && arg.is_empty() {
+                match find_arg(pp, m, args, body.get(i+2)) {
+                    Some(arg2) => out.extend(arg2.iter().cloned()),
+                    None => out.push(body[i+2].clone()),
+                }
+                i += 3;
+                continue;
+            }
+
+            // Operands of "##" are not macro-expanded.
+            let mut arg = if pasted { arg.clone() } else { expand_arg(pp, arg)? };
+            if let Some(first) = arg.first_mut() {
+                first.has_space = tok.has_space;
+                first.at_bol = false;
+            }
+            out.extend(arg);
+            i += 1;
+            continue;
+        }
+
+        out.push(tok.clone());
+        i += 1;
+    }
+    Ok(out)
+}
+
+// If tok is a macro, push its expansion back to the input and return true.
+fn expand_macro(pp: &mut PreprocessContext, tok: &Token) -> Result<bool, Diagnostic> {
+    let name = match ident_name(pp, tok) {
+        Some(name) => name.to_string(),
+        None => return Ok(false),
+    };
+    if tok.hideset.contains(&name) {
+        return Ok(false);
+    }
+    match name.as_str() {
+        "__has_include" => {
+            expand_has_include(pp, tok)?;
+            return Ok(true);
+        }
+        "_Pragma" => {
+            expand_pragma_operator(pp, tok)?;
+            return Ok(true);
+        }
+        // Location of the outermost macro invocation
+        "__FILE__" | "__LINE__" => {
+            let span = tok.origin.as_ref().unwrap_or(&tok.span);
+            let (file, line) = span.presented();
+            let text = match name.as_str() {
+                "__FILE__" => format!("\"{}\"", file.replace('\\', "\\\\").replace('"', "\\\"")),
+                _ => line.to_string(),
+            };
+            let result = builtin_token(pp.names, tok, &text);
+            unget(pp, vec![result]);
+            return Ok(true);
+        }
+        _ => {}
+    }
+    let m = match pp.macros.get(&name) {
+        Some(m) => m.clone(),
+        None => return Ok(false),
+    };
+    expand(pp, tok, &name, &m)
+}
+
+// Source range from the first to the last token
+fn span_of(tokens: &[Token]) -> Span {
+    let mut span = tokens[0].span.clone();
+    span.end = tokens[tokens.len()-1].span.end;
+    span
+}
+
+// Get the file name from `"foo.h"` or `<foo.h>`.
+// Returns the name and whether it is quoted.
+fn parse_include_name(tokens: &[Token]) -> Option<(String, bool)> {
+    let first = tokens.first()?;
+    if first.kind.is_str() && tokens.len() == 1 {
+        let text = first.span.text();
+        return Some((text[1..text.len()-1].to_string(), true));
+    }
+    if is_symbol(first, Symbol::Lt) && tokens.len() > 2 && is_symbol(&tokens[tokens.len()-1], Symbol::Gt) {
+        let mut name = join_tokens(&tokens[1..tokens.len()-1]);
+        if tokens[1].has_space {
+            name.insert(0, ' ');
+        }
+        return Some((name, false));
+    }
+    None
+}
+
+// Read the operand of #include or __has_include. A form that is neither
+// "file" nor <file> is macro-expanded first.
+fn read_include_name(pp: &mut PreprocessContext, tokens: &[Token], directive: &Token, what: &str)
+                     -> Result<(String, bool), Diagnostic> {
+    if let Some(name) = parse_include_name(tokens) {
+        return Ok(name);
+    }
+    let expanded = expand_arg(pp, tokens)?;
+    match parse_include_name(&expanded) {
+        Some(name) => Ok(name),
+        None => {
+            let span = if tokens.is_empty() { directive.span.clone() } else { span_of(tokens) };
+            Err(Diagnostic::error(format!("{} expects \"FILENAME\" or <FILENAME>", what))
+                .with_primary(&span, ""))
+        }
+    }
+}
+
+// Quoted names are searched relative to the including file first.
+fn search_include(pp: &PreprocessContext, name: &str, quoted: bool, from: &Token) -> Option<PathBuf> {
+    if Path::new(name).is_absolute() {
+        return Some(PathBuf::from(name)).filter(|p| p.is_file());
+    }
+
+    let dir = Path::new(&from.span.file.name).parent().map(Path::to_path_buf);
+    let dirs = quoted.then_some(dir).flatten().into_iter()
+        .chain(pp.include_paths.iter().cloned());
+    for dir in dirs {
+        let path = dir.join(name);
+        if path.is_file() {
+            return Some(path);
+        }
+    }
+    None
+}
+
+// include-directive = ("\"" file "\"" | "<" file ">")
+fn include_file(pp: &mut PreprocessContext, directive: &Token) -> Result<(), Diagnostic> {
+    let line = read_line(pp);
+    let (name, quoted) = read_include_name(pp, &line, directive, "#include")?;
+    let span = span_of(&line);
+
+    let path = match search_include(pp, &name, quoted, directive) {
+        Some(path) => path,
+        None => {
+            return Err(Diagnostic::error(format!("'{}' file not found", name))
+                .with_primary(&span, ""));
+        }
+    };
+    if fs::canonicalize(&path).is_ok_and(|p| pp.pragma_once.contains(&p)) {
+        return Ok(());
+    }
+
+    let mut depth = 0;
+    let mut from = Some(&span);
+    while let Some(span) = from {
+        depth += 1;
+        from = span.file.included_from.as_ref();
+    }
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(Diagnostic::error(format!("#include nested depth {} exceeds maximum of {}",
+                                             depth, MAX_INCLUDE_DEPTH))
+            .with_primary(&span, ""));
+    }
+
+    let contents = fs::read_to_string(&path).map_err(|e| {
+        Diagnostic::error(format!("cannot read '{}': {}", path.display(), e)).with_primary(&span, "")
+    })?;
+    let file = SourceFile::included(&path.to_string_lossy(), contents, span);
+    match Lexer::new(file, pp.names).tokenize() {
+        Ok(mut tokens) => {
+            tokens.pop();
+            unget(pp, tokens);
+        }
+        Err(e) => pp.errors.extend(e.diagnostics),
+    }
+    Ok(())
+}
+
+// __has_include("file") or __has_include(<file>) is 1 if the file exists.
+fn expand_has_include(pp: &mut PreprocessContext, tok: &Token) -> Result<(), Diagnostic> {
+    let open = next(pp);
+    if !is_symbol(&open, Symbol::OpeningParen) {
+        return Err(error(&open, "missing '(' after '__has_include'"));
+    }
+    let mut args = vec![];
+    loop {
+        let t = next(pp);
+        if is_symbol(&t, Symbol::ClosingParen) {
+            break;
+        }
+        if t.kind == TokenKind::Eof || t.at_bol {
+            return Err(error(&open, "missing ')' after '__has_include'"));
+        }
+        args.push(t);
+    }
+
+    let (name, quoted) = read_include_name(pp, &args, tok, "__has_include")?;
+    let found = search_include(pp, &name, quoted, tok).is_some();
+    let result = builtin_token(pp.names, tok, if found { "1" } else { "0" });
+    unget(pp, vec![result]);
+    Ok(())
+}
+
+// Token replacing the builtin macro tok
+fn builtin_token(names: &mut Interner, tok: &Token, text: &str) -> Token {
+    let mut result = tokenize_scratch(names, text).unwrap().remove(0);
+    result.at_bol = tok.at_bol;
+    result.has_space = tok.has_space;
+    result.origin = Some(tok.origin.clone().unwrap_or_else(|| tok.span.clone()));
+    result
+}
+
+fn expand(pp: &mut PreprocessContext, tok: &Token, name: &str, m: &Macro) -> Result<bool, Diagnostic> {
+    let (mut body, mut hideset) = match m.params {
+        // Object-like macro
+        None => (subst(pp, m, &[])?, tok.hideset.clone()),
+
+        // A function-like macro name not followed by "(" is an ordinary identifier.
+        Some(_) if !is_symbol(peek(pp), Symbol::OpeningParen) => return Ok(false),
+
+        Some(_) => {
+            next(pp);
+            let (args, rparen) = read_macro_args(pp, tok, m)?;
+            let body = subst(pp, m, &args)?;
+            let hideset = tok.hideset.iter()
+                .filter(|n| rparen.hideset.contains(n))
+                .cloned()
+                .collect();
+            (body, hideset)
+        }
+    };
+    hideset.push(name.to_string());
+    add_hideset(&mut body, &hideset);
+
+    let origin = tok.origin.clone().unwrap_or_else(|| tok.span.clone());
+    for t in &mut body {
+        t.origin = Some(origin.clone());
+    }
+
+    if let Some(first) = body.first_mut() {
+        first.at_bol = tok.at_bol;
+        first.has_space = tok.has_space;
+    }
+    unget(pp, body);
+    Ok(true)
+}
+
+// Name of the directive if tok is "#" starting one
+fn directive_name(pp: &PreprocessContext, tok: &Token) -> Option<String> {
+    if !is_hash(tok) || pp.tokens.len() < 2 {
+        return None;
+    }
+    let name = peek(pp);
+    if name.at_bol { None } else { ident_name(pp, name).map(|name| name.to_string()) }
+}
+
+// Skip tokens until the #elif, #else or #endif ending the current group.
+// Nested conditionals in the skipped group are skipped as a whole.
+fn skip_cond_incl(pp: &mut PreprocessContext) {
+    let mut depth = 0;
+    loop {
+        let tok = next(pp);
+        if tok.kind == TokenKind::Eof {
+            return;
+        }
+        match directive_name(pp, &tok).as_deref() {
+            Some("if") | Some("ifdef") | Some("ifndef") => depth += 1,
+            Some("endif") if depth > 0 => depth -= 1,
+            Some("elif") | Some("else") | Some("endif") if depth == 0 => {
+                unget(pp, vec![tok]);
+                return;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn is_defined(pp: &PreprocessContext, name: &str) -> bool {
+    BUILTIN_MACROS.contains(&name) || pp.macros.contains_key(name)
+}
+
+// Value in a #if expression. All arithmetic is done in intmax_t or uintmax_t.
+#[derive(Clone, Copy)]
+struct Value {
+    val: i64,
+    is_unsigned: bool,
+}
+
+struct EvalContext<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    directive: &'a Token,
+    unevaluated: usize,     // > 0 in an operand skipped by &&, || or ?:
+}
+
+fn eval_error(ec: &EvalContext, msg: &str) -> Diagnostic {
+    match ec.tokens.get(ec.pos) {
+        Some(tok) => error(tok, msg),
+        None => error(&ec.tokens[ec.tokens.len()-1], msg),
+    }
+}
+
+fn eval_consume(ec: &mut EvalContext, sym: Symbol) -> bool {
+    if ec.tokens.get(ec.pos).is_some_and(|t| is_symbol(t, sym)) {
+        ec.pos += 1;
+        return true;
+    }
+    false
+}
+
+fn signed(val: i64) -> Value {
+    Value { val, is_unsigned: false }
+}
+
+// conditional = logor ("?" conditional ":" conditional)?
+fn eval_conditional(ec: &mut EvalContext) -> Result<Value, Diagnostic> {
+    let cond = eval_binary(ec, 0)?;
+    if !eval_consume(ec, Symbol::Question) {
+        return Ok(cond);
+    }
+    let then = eval_unevaluated(ec, cond.val == 0, eval_conditional)?;
+    if !eval_consume(ec, Symbol::Colon) {
+        return Err(eval_error(ec, "expected ':' in #if expression"));
+    }
+    let els = eval_unevaluated(ec, cond.val != 0, eval_conditional)?;
+    let is_unsigned = then.is_unsigned || els.is_unsigned;
+    let val = if cond.val != 0 { then.val } else { els.val };
+    Ok(Value { val, is_unsigned })
+}
+
+// Parse an operand, which is not evaluated if `skip` is true.
+// e.g. `0 && 1/0` is not an error.
+fn eval_unevaluated(ec: &mut EvalContext, skip: bool,
+                    f: impl Fn(&mut EvalContext) -> Result<Value, Diagnostic>) -> Result<Value, Diagnostic> {
+    if skip {
+        ec.unevaluated += 1;
+    }
+    let val = f(ec);
+    if skip {
+        ec.unevaluated -= 1;
+    }
+    val
+}
+
+// Binary operators from the lowest precedence level
+const BINARY_OPS: [&[Symbol]; 10] = [
+    &[Symbol::LogOr],
+    &[Symbol::LogAnd],
+    &[Symbol::Pipe],
+    &[Symbol::Caret],
+    &[Symbol::Ampersand],
+    &[Symbol::Eq, Symbol::Ne],
+    &[Symbol::Lt, Symbol::Le, Symbol::Gt, Symbol::Ge],
+    &[Symbol::Shl, Symbol::Shr],
+    &[Symbol::Add, Symbol::Sub],
+    &[Symbol::Asterisk, Symbol::Div, Symbol::Percent],
+];
+
+// Parse operators of the given precedence level and higher
+fn eval_binary(ec: &mut EvalContext, level: usize) -> Result<Value, Diagnostic> {
+    if level == BINARY_OPS.len() {
+        return eval_unary(ec);
+    }
+
+    let mut lhs = eval_binary(ec, level + 1)?;
+    loop {
+        let op = match ec.tokens.get(ec.pos) {
+            Some(Token { kind: TokenKind::Symbol(sym), .. }) if BINARY_OPS[level].contains(sym) => sym.clone(),
+            _ => return Ok(lhs),
+        };
+        let op_tok = &ec.tokens[ec.pos];
+        ec.pos += 1;
+        let skip = match op {
+            Symbol::LogOr => lhs.val != 0,
+            Symbol::LogAnd => lhs.val == 0,
+            _ => false,
+        };
+        let rhs = eval_unevaluated(ec, skip, |ec| eval_binary(ec, level + 1))?;
+
+        let is_unsigned = lhs.is_unsigned || rhs.is_unsigned;
+        let (l, r) = (lhs.val, rhs.val);
+        lhs = match op {
+            Symbol::LogOr => signed((l != 0 || r != 0) as i64),
+            Symbol::LogAnd => signed((l != 0 && r != 0) as i64),
+            Symbol::Pipe => Value { val: l | r, is_unsigned },
+            Symbol::Caret => Value { val: l ^ r, is_unsigned },
+            Symbol::Ampersand => Value { val: l & r, is_unsigned },
+            Symbol::Eq => signed((l == r) as i64),
+            Symbol::Ne => signed((l != r) as i64),
+            Symbol::Lt if is_unsigned => signed(((l as u64) < r as u64) as i64),
+            Symbol::Le if is_unsigned => signed((l as u64 <= r as u64) as i64),
+            Symbol::Gt if is_unsigned => signed((l as u64 > r as u64) as i64),
+            Symbol::Ge if is_unsigned => signed((l as u64 >= r as u64) as i64),
+            Symbol::Lt => signed((l < r) as i64),
+            Symbol::Le => signed((l <= r) as i64),
+            Symbol::Gt => signed((l > r) as i64),
+            Symbol::Ge => signed((l >= r) as i64),
+            Symbol::Shl => Value { val: l.wrapping_shl(r as u32), ..lhs },
+            Symbol::Shr if lhs.is_unsigned => Value { val: (l as u64).wrapping_shr(r as u32) as i64, ..lhs },
+            Symbol::Shr => Value { val: l.wrapping_shr(r as u32), ..lhs },
+            Symbol::Add => Value { val: l.wrapping_add(r), is_unsigned },
+            Symbol::Sub => Value { val: l.wrapping_sub(r), is_unsigned },
+            Symbol::Asterisk => Value { val: l.wrapping_mul(r), is_unsigned },
+            Symbol::Div | Symbol::Percent if r == 0 && ec.unevaluated == 0 => {
+                return Err(error(op_tok, "division by zero in preprocessor expression"));
+            }
+            Symbol::Div | Symbol::Percent if r == 0 => signed(0),
+            Symbol::Div if is_unsigned => Value { val: (l as u64 / r as u64) as i64, is_unsigned },
+            Symbol::Percent if is_unsigned => Value { val: (l as u64 % r as u64) as i64, is_unsigned },
+            Symbol::Div => Value { val: l.wrapping_div(r), is_unsigned },
+            _ => Value { val: l.wrapping_rem(r), is_unsigned },
+        };
+    }
+}
+
+// unary = ("+" | "-" | "!" | "~") unary | primary
+// primary = "(" conditional ")" | num
+fn eval_unary(ec: &mut EvalContext) -> Result<Value, Diagnostic> {
+    if eval_consume(ec, Symbol::Add) {
+        return eval_unary(ec);
+    }
+    if eval_consume(ec, Symbol::Sub) {
+        let v = eval_unary(ec)?;
+        return Ok(Value { val: v.val.wrapping_neg(), ..v });
+    }
+    if eval_consume(ec, Symbol::Not) {
+        let v = eval_unary(ec)?;
+        return Ok(signed((v.val == 0) as i64));
+    }
+    if eval_consume(ec, Symbol::Tilde) {
+        let v = eval_unary(ec)?;
+        return Ok(Value { val: !v.val, ..v });
+    }
+    if eval_consume(ec, Symbol::OpeningParen) {
+        let v = eval_conditional(ec)?;
+        if !eval_consume(ec, Symbol::ClosingParen) {
+            return Err(eval_error(ec, "missing ')' in #if expression"));
+        }
+        return Ok(v);
+    }
+
+    let tok = match ec.tokens.get(ec.pos) {
+        Some(tok) => tok,
+        None if ec.tokens.is_empty() => {
+            return Err(error(ec.directive, &format!("#{} with no expression", ec.directive.span.text())));
+        }
+        None => return Err(error(&ec.tokens[ec.pos-1], "expected value in expression")),
+    };
+    match tok.kind {
+        TokenKind::Num(val, ity) => {
+            ec.pos += 1;
+            let is_unsigned = matches!(ity, IntType::UInt | IntType::ULong | IntType::ULongLong);
+            Ok(Value { val, is_unsigned })
+        }
+        TokenKind::Invalid(ref diag) => Err(*diag.clone()),
+        _ => Err(error(tok, &format!("token {} is not valid in preprocessor expressions", tok))),
+    }
+}
+
+// Read and evaluate the constant expression of #if or #elif.
+fn eval_const_expr(pp: &mut PreprocessContext, directive: &Token) -> Result<bool, Diagnostic> {
+    let line = read_line(pp);
+
+    // Replace "defined(X)" and "defined X" with 1 or 0 before expanding macros.
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < line.len() {
+        let tok = &line[i];
+        i += 1;
+        if ident_name(pp, tok).as_deref() != Some("defined") {
+            tokens.push(tok.clone());
+            continue;
+        }
+
+        let paren = line.get(i).is_some_and(|t| is_symbol(t, Symbol::OpeningParen));
+        if paren {
+            i += 1;
+        }
+        let name = match line.get(i).and_then(|t| ident_name(pp, t)) {
+            Some(name) => name,
+            None => return Err(error(line.get(i).unwrap_or(tok), "macro name missing after 'defined'")),
+        };
+        i += 1;
+        if paren {
+            if !line.get(i).is_some_and(|t| is_symbol(t, Symbol::ClosingParen)) {
+                return Err(error(line.get(i).unwrap_or(&line[i-1]), "missing ')' after 'defined'"));
+            }
+            i += 1;
+        }
+
+        let val = if is_defined(pp, &name) { "1" } else { "0" };
+        let mut num = tokenize_scratch(pp.names, val).unwrap().remove(0);
+        num.span = tok.span.clone();
+        tokens.push(num);
+    }
+
+    // Identifiers remaining after macro expansion are 0.
+    let mut tokens = expand_arg(pp, &tokens)?;
+    for tok in &mut tokens {
+        if ident_name(pp, tok).is_some() {
+            let mut zero = tokenize_scratch(pp.names, "0").unwrap().remove(0);
+            zero.span = tok.span.clone();
+            *tok = zero;
+        }
+    }
+
+    let mut ec = EvalContext { tokens: &tokens, pos: 0, directive, unevaluated: 0 };
+    let val = eval_conditional(&mut ec)?;
+    if let Some(tok) = tokens.get(ec.pos) {
+        return Err(error(tok, &format!("extra token {} in #{} expression", tok.kind, directive.span.text())));
+    }
+    Ok(val.val != 0)
+}
+
+// Start a conditional group and skip it unless `included` is true.
+fn push_cond_incl(pp: &mut PreprocessContext, tok: &Token, included: bool) {
+    pp.cond_incl.push(CondIncl { ctx: CondCtx::Then, tok: tok.clone(), included });
+    if !included {
+        skip_cond_incl(pp);
+    }
+}
+
+// Execute a pragma given by #pragma or _Pragma. Unknown pragmas are ignored.
+fn do_pragma(pp: &mut PreprocessContext, tokens: &[Token], loc: &Token) {
+    if tokens.len() == 1 && ident_name(pp, &tokens[0]).as_deref() == Some("once") {
+        let span = loc.origin.as_ref().unwrap_or(&loc.span);
+        if let Ok(path) = fs::canonicalize(&span.file.name) {
+            pp.pragma_once.insert(path);
+        }
+        return;
+    }
+
+    let text = if tokens.is_empty() { String::new() } else { format!(" {}", join_tokens(tokens)) };
+    pp.warnings.push(Diagnostic::warning(format!("ignoring #pragma{}", text))
+        .with_primary(&loc.span, "unknown pragma"));
+}
+
+// _Pragma("...") is the same as #pragma ...
+fn expand_pragma_operator(pp: &mut PreprocessContext, tok: &Token) -> Result<(), Diagnostic> {
+    let mut args = vec![];
+    for _ in 0..3 {
+        args.push(next(pp));
+    }
+    let valid = is_symbol(&args[0], Symbol::OpeningParen)
+        && args[1].kind.is_str()
+        && is_symbol(&args[2], Symbol::ClosingParen);
+    if !valid {
+        return Err(error(tok, "_Pragma takes a parenthesized string literal"));
+    }
+
+    let text = args[1].span.text();
+    let text = text[1..text.len()-1].replace("\\\"", "\"").replace("\\\\", "\\");
+    let tokens = tokenize_scratch(pp.names, &text).unwrap_or_default();
+    do_pragma(pp, &tokens, tok);
+    Ok(())
+}
+
+// line-directive = num ("\"" file "\"")?
+//
+// The line after the directive becomes line `num` of `file`. Spans keep the
+// physical file for #include and #pragma once; only the presented location
+// changes.
+fn line_directive(pp: &mut PreprocessContext, line: &[Token], hash: &Token) -> Result<(), Diagnostic> {
+    let line = if line.first().is_some_and(|t| t.kind.is_num()) {
+        line.to_vec()
+    } else {
+        expand_arg(pp, line)?
+    };
+
+    let num = match line.first().map(|t| &t.kind) {
+        Some(TokenKind::Num(n, _)) if *n > 0 => *n as usize,
+        _ => return Err(error(line.first().unwrap_or(hash), "#line directive requires a positive integer argument")),
+    };
+    let name = match line.get(1) {
+        Some(t) if t.kind.is_str() => {
+            let text = t.span.text();
+            Some(text[1..text.len()-1].to_string())
+        }
+        Some(t) => return Err(error(t, "invalid filename in #line directive")),
+        None => None,
+    };
+    if let Some(t) = line.get(2) {
+        return Err(error(t, "extra tokens at end of #line directive"));
+    }
+
+    // Line numbers are counted from the line after the directive.
+    hash.span.file.add_line_marker(hash.span.line + 1, name, num);
+    Ok(())
+}
+
+fn directive(pp: &mut PreprocessContext, hash: &Token) -> Result<(), Diagnostic> {
+    // Null directive
+    if peek(pp).at_bol || peek(pp).kind == TokenKind::Eof {
+        return Ok(());
+    }
+
+    let tok = next(pp);
+    match ident_name(pp, &tok).as_deref() {
+        Some("define") => read_define(pp, hash),
+        Some("include") => include_file(pp, &tok),
+        Some("undef") => {
+            let line = read_line(pp);
+            match line.first().and_then(|t| ident_name(pp, t)) {
+                Some(name) => {
+                    pp.macros.remove(&*name);
+                    Ok(())
+                }
+                None => Err(error(line.first().unwrap_or(hash), "macro name must be an identifier")),
+            }
+        }
+        Some("if") => {
+            let val = eval_const_expr(pp, &tok).unwrap_or_else(|diag| {
+                pp.errors.push(diag);
+                false
+            });
+            push_cond_incl(pp, &tok, val);
+            Ok(())
+        }
+        Some("ifdef") | Some("ifndef") => {
+            let line = read_line(pp);
+            let name = match line.first().and_then(|t| ident_name(pp, t)) {
+                Some(name) => name,
+                None => {
+                    push_cond_incl(pp, &tok, false);
+                    return Err(error(&tok, &format!("no macro name given in #{} directive", tok.span.text())));
+                }
+            };
+            let defined = is_defined(pp, &name);
+            push_cond_incl(pp, &tok, defined == (ident_name(pp, &tok).as_deref() == Some("ifdef")));
+            Ok(())
+        }
+        Some("elif") => {
+            let included = match pp.cond_incl.last_mut() {
+                Some(ci) if ci.ctx != CondCtx::Else => {
+                    ci.ctx = CondCtx::Elif;
+                    ci.included
+                }
+                Some(_) => return Err(error(&tok, "#elif after #else")),
+                None => return Err(error(&tok, "#elif without #if")),
+            };
+            if included {
+                read_line(pp);
+                skip_cond_incl(pp);
+                return Ok(());
+            }
+
+            let val = eval_const_expr(pp, &tok).unwrap_or_else(|diag| {
+                pp.errors.push(diag);
+                false
+            });
+            if val {
+                pp.cond_incl.last_mut().unwrap().included = true;
+            } else {
+                skip_cond_incl(pp);
+            }
+            Ok(())
+        }
+        Some("else") => {
+            read_line(pp);
+            let included = match pp.cond_incl.last_mut() {
+                Some(ci) if ci.ctx != CondCtx::Else => {
+                    ci.ctx = CondCtx::Else;
+                    std::mem::replace(&mut ci.included, true)
+                }
+                Some(_) => return Err(error(&tok, "#else after #else")),
+                None => return Err(error(&tok, "#else without #if")),
+            };
+            if included {
+                skip_cond_incl(pp);
+            }
+            Ok(())
+        }
+        Some("endif") => {
+            read_line(pp);
+            if pp.cond_incl.pop().is_none() {
+                return Err(error(&tok, "#endif without #if"));
+            }
+            Ok(())
+        }
+        Some("pragma") => {
+            let line = read_line(pp);
+            do_pragma(pp, &line, &tok);
+            Ok(())
+        }
+        Some("error") | Some("warning") => {
+            let line = read_line(pp);
+            let text = if line.is_empty() { String::new() } else { format!(" {}", span_of(&line).text()) };
+            let msg = format!("#{}{}", tok.span.text(), text);
+            if ident_name(pp, &tok).as_deref() == Some("error") {
+                pp.errors.push(error(&tok, &msg));
+            } else {
+                pp.warnings.push(Diagnostic::warning(msg).with_primary(&tok.span, ""));
+            }
+            Ok(())
+        }
+        Some("line") => {
+            let line = read_line(pp);
+            line_directive(pp, &line, hash)
+        }
+        // [GNU] Linemarker: # 33 "foo.c" 2
+        _ if tok.kind.is_num() => {
+            let mut line = read_line(pp);
+            line.insert(0, tok);
+            line.truncate(2);
+            line_directive(pp, &line, hash)
+        }
+        _ => Err(error(&tok, &format!("invalid preprocessing directive #{}", tok.span.text()))),
+    }
+}
+
+// Current UTC date and time as "Mmm dd yyyy" and "hh:mm:ss"
+fn date_and_time() -> (String, String) {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // Convert days since 1970-01-01 to a civil date.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    let months = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                  "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let date = format!("{} {:>2} {}", months[month as usize - 1], day, year);
+    let time = format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+    (date, time)
+}
+
+// Define a macro as if by "#define name value". The name may have a
+// parameter list, e.g. "F(x)".
+fn define_macro(pp: &mut PreprocessContext, name: &str, value: &str) -> Result<(), Diagnostic> {
+    let text = format!("{} {}", name, value);
+    let mut tokens = match tokenize_scratch(pp.names, &text) {
+        Some(tokens) if !tokens.is_empty() => tokens,
+        _ => return Err(Diagnostic::error(format!("invalid macro definition '{}'", text.trim()))),
+    };
+    tokens[0].at_bol = false;
+    let first = tokens[0].clone();
+
+    let eof = pp.tokens[0].clone();
+    let saved = std::mem::replace(&mut pp.tokens, vec![eof]);
+    unget(pp, tokens);
+    let result = read_define(pp, &first);
+    pp.tokens = saved;
+    result
+}
+
+fn init_macros(pp: &mut PreprocessContext, opts: &PreprocessOptions) -> Result<(), Diagnostic> {
+    let (date, time) = date_and_time();
+    let predefined = [
+        ("__STDC__", "1"),
+        ("__STDC_VERSION__", "201112L"),
+        ("__STDC_HOSTED__", "1"),
+        ("__x86_64__", "1"),
+        ("__x86_64", "1"),
+        ("__linux__", "1"),
+        ("__linux", "1"),
+        ("__unix__", "1"),
+        ("__LP64__", "1"),
+        ("_LP64", "1"),
+        ("__toycc__", "1"),
+        ("__DATE__", &format!("\"{}\"", date)),
+        ("__TIME__", &format!("\"{}\"", time)),
+    ];
+    for (name, value) in predefined.iter() {
+        define_macro(pp, name, value)?;
+    }
+
+    for opt in &opts.macros {
+        match opt {
+            MacroOption::Define(name, value) => define_macro(pp, name, value)?,
+            MacroOption::Undef(name) => {
+                pp.macros.remove(name);
+            }
+        }
+    }
+    Ok(())
+}
+
+// Expand macros and execute directives. Warnings are appended to
+// `warnings` whether or not preprocessing succeeds.
+pub fn preprocess(mut tokens: Vec<Token>, opts: &PreprocessOptions, names: &mut Interner,
+                  warnings: &mut Vec<Diagnostic>) -> Result<Vec<Token>, Error> {
+    tokens.reverse();
+    let mut pp = PreprocessContext {
+        tokens,
+        names,
+        macros: HashMap::new(),
+        errors: vec![],
+        warnings: vec![],
+        include_paths: opts.include_paths.iter().cloned()
+            .chain(SYSTEM_INCLUDE_PATHS.iter().map(PathBuf::from))
+            .collect(),
+        pragma_once: HashSet::new(),
+        cond_incl: vec![],
+    };
+    let res = preprocess2(&mut pp, opts);
+    warnings.append(&mut pp.warnings);
+    res
+}
+
+// Main loop of the preprocessor
+fn preprocess2(pp: &mut PreprocessContext, opts: &PreprocessOptions) -> Result<Vec<Token>, Error> {
+    init_macros(pp, opts)?;
+
+    let mut out = vec![];
+    loop {
+        let tok = next(pp);
+        if tok.kind == TokenKind::Eof {
+            out.push(tok);
+            break;
+        }
+
+        if is_hash(&tok) {
+            if let Err(diag) = directive(pp, &tok) {
+                pp.errors.push(diag);
+                read_line(pp);
+            }
+            continue;
+        }
+
+        match expand_macro(pp, &tok) {
+            Ok(true) => {}
+            Ok(false) => match tok.kind {
+                TokenKind::Invalid(diag) => pp.errors.push(*diag),
+                _ => out.push(tok),
+            },
+            Err(diag) => pp.errors.push(diag),
+        }
+    }
+
+    for ci in &pp.cond_incl {
+        pp.errors.push(error(&ci.tok, &format!("unterminated #{}", ci.tok.span.text())));
+    }
+
+    if !pp.errors.is_empty() {
+        return Err(Error { diagnostics: std::mem::take(&mut pp.errors) });
+    }
+    Ok(out)
+}
+
+// Two tokens written next to each other would lex as one token
+// (e.g. "-" "-" or "x" "1"), so a space is needed between them.
+fn needs_space(prev: &str, next: &str) -> bool {
+    let (a, b) = match (prev.chars().last(), next.chars().next()) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return false,
+    };
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_' || c == '.';
+    if is_ident(a) && is_ident(b) {
+        return true;
+    }
+    let pair = format!("{}{}", a, b);
+    ["==", "!=", "<=", ">=", "&&", "||", "<<", ">>", "++", "--", "+=", "-=", "*=", "/=",
+     "%=", "&=", "|=", "^=", "##", "->", "//", "/*", "..", "%:", "<:", "<%", "%>", ":>"]
+        .contains(&pair.as_str())
+}
+
+fn linemarker(out: &mut String, line: usize, name: &str, flag: Option<u8>) {
+    let name = name.replace('\\', "\\\\").replace('"', "\\\"");
+    out.push_str(&format!("# {} \"{}\"", line, name));
+    if let Some(flag) = flag {
+        out.push_str(&format!(" {}", flag));
+    }
+    out.push('\n');
+}
+
+// True if `file` is (directly or indirectly) included from `parent`.
+fn is_included_from(file: &SourceFile, parent: &Rc<SourceFile>) -> bool {
+    let mut from = &file.included_from;
+    while let Some(span) = from {
+        if Rc::ptr_eq(&span.file, parent) {
+            return true;
+        }
+        from = &span.file.included_from;
+    }
+    false
+}
+
+// Write preprocessed tokens back out as source text for -E. The output uses
+// GCC-style linemarkers "# N "file" flags", where flag 1 means entering an
+// included file and 2 means returning to the includer.
+pub fn print_tokens(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    let mut file: Option<Rc<SourceFile>> = None;
+    let mut name: Rc<str> = Rc::from("");
+    let mut line = 0;
+    let mut prev: Option<&Token> = None;
+
+    // Start with the main file even if the first token comes from a header.
+    if let Some(tok) = tokens.first() {
+        let first = &tok.origin.as_ref().unwrap_or(&tok.span).file;
+        let mut main = first;
+        while let Some(span) = &main.included_from {
+            main = &span.file;
+        }
+        if tok.kind != TokenKind::Eof && !Rc::ptr_eq(main, first) {
+            linemarker(&mut out, 1, &main.name, None);
+            file = Some(main.clone());
+            name = Rc::from(main.name.as_str());
+            line = 1;
+        }
+    }
+
+    for tok in tokens {
+        if tok.kind == TokenKind::Eof {
+            break;
+        }
+
+        // Expanded tokens are placed at the macro invocation.
+        let loc = tok.origin.as_ref().unwrap_or(&tok.span);
+        let at_start = prev.is_none();
+        let (loc_name, loc_line) = loc.presented();
+
+        let same_file = file.as_ref().is_some_and(|f| Rc::ptr_eq(f, &loc.file)) && name == loc_name;
+        if !same_file {
+            let flag = match &file {
+                Some(f) if is_included_from(&loc.file, f) => Some(1),
+                Some(f) if is_included_from(f, &loc.file) => Some(2),
+                _ => None,
+            };
+            if !at_start && !out.ends_with('\n') {
+                out.push('\n');
+            }
+            linemarker(&mut out, loc_line, &loc_name, flag);
+            file = Some(loc.file.clone());
+            name = loc_name;
+            line = loc_line;
+            prev = None;
+        } else if tok.at_bol {
+            // Short gaps are filled with blank lines, longer ones with a marker.
+            if loc_line > line && loc_line - line <= 8 {
+                out.push_str(&"\n".repeat(loc_line - line));
+            } else {
+                out.push('\n');
+                linemarker(&mut out, loc_line, &loc_name, None);
+            }
+            line = loc_line;
+            prev = None;
+        }
+
+        let text = tok.span.text();
+        if let Some(prev) = prev {
+            let adjacent = Rc::ptr_eq(&prev.span.file, &tok.span.file) && prev.span.end == tok.span.start;
+            if tok.has_space || (!adjacent && needs_space(&prev.span.text(), &text)) {
+                out.push(' ');
+            }
+        } else if tok.has_space && loc.col > 1 {
+            out.push(' ');
+        }
+        out.push_str(&text);
+        prev = Some(tok);
+    }
+
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out
+}
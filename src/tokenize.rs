@@ -1,50 +1,145 @@
-use std::process;
+use super::diagnostic::{ Diagnostic, Error };
+use super::intern::{ Interner, Name };
+use super::unicode::{ is_ident1, is_ident2 };
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
 
 #[derive(Debug)]
-pub struct Lexer {
-    code: Vec<char>,
-    pos: usize,
+pub struct Lexer<'a> {
+    file: Rc<SourceFile>,
+    names: &'a mut Interner,    // Interner for identifiers
+    pos: usize,         // Byte offset in file.contents
+
+    // Location of `pos` in the source file
+    line: usize,
+    col: usize,
+
+    // Flags for the next token
+    at_bol: bool,
+    has_space: bool,
+}
+
+// Input file
+#[derive(PartialEq)]
+pub struct SourceFile {
+    pub name: String,
+    pub contents: String,
+    pub included_from: Option<Span>,    // Location of the #include
+    line_markers: RefCell<Vec<LineMarker>>, // Set by #line, sorted by line
+}
+
+// From physical line `line` on, lines are presented as `presented_line`
+// and onward of `name`.
+#[derive(PartialEq)]
+struct LineMarker {
+    line: usize,
+    name: Rc<str>,
+    presented_line: usize,
+}
+
+// Position of a token in its source file.
+// line and col are 1-origin, start and end are byte offsets.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Span {
+    pub file: Rc<SourceFile>,
+    pub line: usize,
+    pub col: usize,
+    pub start: usize,
+    pub end: usize,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Token {
     pub kind: TokenKind,    // Kind of Token
+    pub span: Span,         // Token location
+    pub at_bol: bool,       // True if this token is at beginning of line
+    pub has_space: bool,    // True if this token follows a space character
+    pub hideset: Vec<String>,   // Macros not to be expanded again
+    pub origin: Option<Span>,   // Macro invocation this token was expanded from
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum TokenKind {
     Keyword(Keyword), // Keyword
     Symbol(Symbol),     // Symbol
-    Ident(String),      // Identifiers
-    Num(i64),           // Numeric literal
+    Ident(Name),  // Identifiers
+    Num(i64, IntType),  // Numeric literal or character constant
+    Str(Vec<u8>),       // String literal without the terminating NUL
+    Invalid(Box<Diagnostic>),   // Malformed token and why it is invalid
     Eof,                // End-of-file markers
 }
 
+// Type of an integer literal
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IntType {
+    Int,
+    UInt,
+    Long,
+    ULong,
+    LongLong,
+    ULongLong,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Symbol {
     Add,            // +
     Sub,            // -
     Div,            // /
+    Percent,        // %
+    Shl,            // <<
+    Shr,            // >>
     Eq,             // ==
     Ne,             // !=
     Lt,             // <
     Le,             // <=
     Gt,             // >
     Ge,             // >=
+    LogAnd,         // &&
+    LogOr,          // ||
+    Not,            // !
     Assign,         // =
+    AddAssign,      // +=
+    SubAssign,      // -=
+    MulAssign,      // *=
+    DivAssign,      // /=
+    ModAssign,      // %=
+    AndAssign,      // &=
+    OrAssign,       // |=
+    XorAssign,      // ^=
+    ShlAssign,      // <<=
+    ShrAssign,      // >>=
+    Inc,            // ++
+    Dec,            // --
     Ampersand,      // &
+    Pipe,           // |
+    Caret,          // ^
+    Tilde,          // ~
     Asterisk,       // *
     Comma,          // ,
+    Question,       // ?
+    Colon,          // :
     Semicolon,      // ;
     OpeningParen,   // (
     ClosingParen,   // )
     OpeningBrace,   // {
     ClosingBrace,   // }
+    OpeningBracket, // [
+    ClosingBracket, // ]
+    Dot,            // .
+    Arrow,          // ->
+    Hash,           // #
+    HashHash,       // ##
+    Ellipsis,       // ...
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Keyword {
+    Char,       // "char"
+    Short,      // "short"
     Int,        // "int"
+    Long,       // "long"
     If,         // "if"
     Else,       // "else"
     For,        // "for"
@@ -52,130 +147,694 @@ pub enum Keyword {
     Return,     // "return"
 }
 
-macro_rules! retrieve_ident {
-    ($e:expr) => {
-        match &$e.kind {
-            &TokenKind::Ident(ref ident) => ident.to_string(),
-            _ => "".to_string(),
+// Name of the buffer holding text created by the preprocessor
+const SCRATCH: &str = "<scratch space>";
+
+impl SourceFile {
+    pub fn new(name: &str, contents: String) -> Rc<SourceFile> {
+        Rc::new(SourceFile {
+            name: name.to_string(),
+            contents,
+            included_from: None,
+            line_markers: RefCell::new(Vec::new()),
+        })
+    }
+
+    // File read by the #include at `from`
+    pub fn included(name: &str, contents: String, from: Span) -> Rc<SourceFile> {
+        Rc::new(SourceFile {
+            name: name.to_string(),
+            contents,
+            included_from: Some(from),
+            line_markers: RefCell::new(Vec::new()),
+        })
+    }
+
+    // Buffer for text created by the preprocessor, e.g. by "#" or "##"
+    pub fn scratch(contents: String) -> Rc<SourceFile> {
+        SourceFile::new(SCRATCH, contents)
+    }
+
+    pub fn is_scratch(&self) -> bool {
+        self.name == SCRATCH
+    }
+
+    // Present physical line `line` and the lines after it as line
+    // `presented_line` of `name` (or of the current name if None).
+    pub fn add_line_marker(&self, line: usize, name: Option<String>, presented_line: usize) {
+        let name = match name {
+            Some(name) => Rc::from(name),
+            None => self.presented(line).0,
+        };
+        self.line_markers.borrow_mut().push(LineMarker { line, name, presented_line });
+    }
+
+    // File name and line number shown for physical line `line`
+    pub fn presented(&self, line: usize) -> (Rc<str>, usize) {
+        let markers = self.line_markers.borrow();
+        let i = markers.partition_point(|m| m.line <= line);
+        match i.checked_sub(1).map(|i| &markers[i]) {
+            Some(m) => (m.name.clone(), m.presented_line + line - m.line),
+            None => (Rc::from(self.name.as_str()), line),
         }
-    };
+    }
+}
+
+// Files are shared by every token, so print only the name.
+impl fmt::Debug for SourceFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SourceFile({:?})", self.name)
+    }
 }
 
-impl Lexer {
-    pub fn new(args: &String) -> Lexer {
-        let code: Vec<char> = args.chars().collect();
-        Lexer { 
-            code,
-            pos: 0 
+impl Span {
+    // File name and line number as changed by #line
+    pub fn presented(&self) -> (Rc<str>, usize) {
+        self.file.presented(self.line)
+    }
+
+    // Source text covered by the span, with backslash-newlines removed
+    pub fn text(&self) -> Cow<'_, str> {
+        let text = &self.file.contents[self.start..self.end];
+        if text.contains("\\\n") {
+            Cow::Owned(text.replace("\\\n", ""))
+        } else {
+            Cow::Borrowed(text)
         }
     }
+}
 
-    // codeのpos番目の文字を取得
-    fn getc(&self) -> &char {
-        match self.code.get(self.pos) {
-            Some(c) => c,
-            None => panic!("doesn’t exists token")
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, line) = self.presented();
+        write!(f, "{}:{}:{}", name, line, self.col)
+    }
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(file: Rc<SourceFile>, names: &'a mut Interner) -> Lexer<'a> {
+        Lexer {
+            file,
+            names,
+            pos: 0,
+            line: 1,
+            col: 1,
+            at_bol: true,
+            has_space: false,
+        }
+    }
+
+    // Backslash-newlines are removed before tokenization (translation
+    // phase 2). The lexer skips them as if they were not there, so spans
+    // still point into the original text.
+
+    // posから続く行の連結の長さ
+    fn splice_len(&self, pos: usize) -> usize {
+        let bytes = self.file.contents.as_bytes();
+        let mut p = pos;
+        while bytes.get(p) == Some(&b'\\') && bytes.get(p + 1) == Some(&b'\n') {
+            p += 2;
+        }
+        p - pos
+    }
+
+    // 行の連結を除いてpos+n番目の位置
+    fn logical_pos(&self, n: usize) -> usize {
+        let mut p = self.pos + self.splice_len(self.pos);
+        for _ in 0..n {
+            p += 1;
+            p += self.splice_len(p);
+        }
+        p
+    }
+
+    // 行の連結を除いてpos+n番目のバイトを取得
+    fn peek(&self, n: usize) -> Option<u8> {
+        self.file.contents.as_bytes().get(self.logical_pos(n)).copied()
+    }
+
+    // posから始まる1文字を取得
+    fn peek_char(&self) -> Option<char> {
+        self.file.contents.get(self.logical_pos(0)..)?.chars().next()
+    }
+
+    // posから続く行の連結を読み飛ばす
+    fn skip_splices(&mut self) {
+        let len = self.splice_len(self.pos);
+        self.line += len / 2;
+        if len > 0 {
+            self.col = 1;
         }
+        self.pos += len;
     }
 
-    // posをn進める
+    // 行の連結を除いてposをnバイト進める
     fn next_pos(&mut self, n: usize) {
-        self.pos += n;
+        for _ in 0..n {
+            self.skip_splices();
+            let b = match self.file.contents.as_bytes().get(self.pos) {
+                Some(&b) => b,
+                None => return,
+            };
+            if b == b'\n' {
+                self.line += 1;
+                self.col = 1;
+            } else if b & 0xc0 != 0x80 {
+                // Count characters, not UTF-8 continuation bytes.
+                self.col += 1;
+            }
+            self.pos += 1;
+        }
+    }
+
+    // 現在位置から始まるトークンの位置
+    fn mark(&self) -> Span {
+        Span {
+            file: self.file.clone(),
+            line: self.line,
+            col: self.col,
+            start: self.pos,
+            end: self.pos,
+        }
     }
 
-    // posがcodeの最後の示しているか
-    fn is_last(&self) -> bool {
-        self.code.len() == self.pos
+    // markから現在位置までの範囲
+    fn span_from(&self, mut start: Span) -> Span {
+        start.end = self.pos;
+        start
     }
 
-    // 12+34があったら12までをi64に変換し、
-    // posを進める。posは+の位置になる
-    fn strtol(&mut self) -> i64 {
-        let mut s = String::new();
-        while let Some(c) = self.code.get(self.pos) {
-            if c.is_ascii_digit() {
-                s.push_str(&c.to_string());
-                self.pos += 1;
-            } else {
-                break;
+    // markから現在位置までのトークンを作る
+    fn new_token(&mut self, kind: TokenKind, start: Span) -> Token {
+        let token = Token {
+            kind,
+            span: self.span_from(start),
+            at_bol: self.at_bol,
+            has_space: self.has_space,
+            hideset: vec![],
+            origin: None,
+        };
+        self.at_bol = false;
+        self.has_space = false;
+        token
+    }
+
+    // バックスラッシュの次の文字から1文字分のエスケープシーケンスを読む
+    fn read_escaped_char(&mut self) -> Result<u32, Diagnostic> {
+        let start = self.mark();
+        let c = match self.peek_char() {
+            Some(c) => c,
+            None => return Err(error(&start, "unterminated escape sequence")),
+        };
+
+        // Octal escape sequence: \ooo
+        if ('0'..='7').contains(&c) {
+            let mut val = 0;
+            let mut n = 0;
+            while let Some(d) = self.peek(0).and_then(|b| (b as char).to_digit(8)) {
+                if n == 3 { break; }
+                val = val * 8 + d;
+                n += 1;
+                self.next_pos(1);
             }
+            if val > 0xff {
+                return Err(error(&self.span_from(start), "octal escape sequence out of range"));
+            }
+            return Ok(val);
         }
 
-        match s.parse::<i64>() {
-            Ok(n) => n,
-            Err(e) => panic!("can't convert to numbers: {}", e)
+        // Hexadecimal escape sequence: \xhh
+        if c == 'x' {
+            self.next_pos(1);
+            let mut val: u32 = 0;
+            let mut n = 0;
+            while let Some(d) = self.peek(0).and_then(|b| (b as char).to_digit(16)) {
+                val = val.saturating_mul(16).saturating_add(d);
+                n += 1;
+                self.next_pos(1);
+            }
+            if n == 0 {
+                return Err(error(&self.span_from(start), "\\x used with no following hex digits"));
+            }
+            if val > 0xff {
+                return Err(error(&self.span_from(start), "hex escape sequence out of range"));
+            }
+            return Ok(val);
         }
+
+        self.next_pos(c.len_utf8());
+        let val = match c {
+            'a' => 7,
+            'b' => 8,
+            't' => 9,
+            'n' => 10,
+            'v' => 11,
+            'f' => 12,
+            'r' => 13,
+            // [GNU] \e for the ASCII escape character
+            'e' => 27,
+            // \\, \', \", \? and unknown escapes are the character itself.
+            _ => c as u32,
+        };
+        Ok(val)
     }
-}
 
-impl Token {
-    fn new(kind: TokenKind) -> Self {
-        Self { kind }
-    }
-
-    fn convert_symbol(self) -> Self {
-        let ident = retrieve_ident!(self);
-        let kind = match ident.as_str() {
-            "+"  => TokenKind::Symbol(Symbol::Add),
-            "-"  => TokenKind::Symbol(Symbol::Sub),
-            "*"  => TokenKind::Symbol(Symbol::Asterisk),
-            "/"  => TokenKind::Symbol(Symbol::Div),
-            "==" => TokenKind::Symbol(Symbol::Eq),
-            "!=" => TokenKind::Symbol(Symbol::Ne),
-            "<"  => TokenKind::Symbol(Symbol::Lt),
-            "<=" => TokenKind::Symbol(Symbol::Le),
-            ">"  => TokenKind::Symbol(Symbol::Gt),
-            ">=" => TokenKind::Symbol(Symbol::Ge),
-            "="  => TokenKind::Symbol(Symbol::Assign),
-            "&"  => TokenKind::Symbol(Symbol::Ampersand),
-            ","  => TokenKind::Symbol(Symbol::Comma),
-            ";"  => TokenKind::Symbol(Symbol::Semicolon),
-            "("  => TokenKind::Symbol(Symbol::OpeningParen),
-            ")"  => TokenKind::Symbol(Symbol::ClosingParen),
-            "{"  => TokenKind::Symbol(Symbol::OpeningBrace),
-            "}"  => TokenKind::Symbol(Symbol::ClosingBrace),
-            _    => return self 
+    // 識別子の1文字を読む。UCNはデコードした文字を返す
+    fn read_ident_char(&mut self, first: bool) -> Result<Option<char>, Diagnostic> {
+        let is_ident = if first { is_ident1 } else { is_ident2 };
+
+        if self.peek(0) == Some(b'\\') && matches!(self.peek(1), Some(b'u') | Some(b'U')) {
+            let start = self.mark();
+            let c = self.read_ucn()?;
+            // UCNs can't name basic characters (C11 6.4.3p2).
+            if c < '\u{a0}' || !is_ident(c) {
+                let span = self.span_from(start);
+                let msg = format!("universal character {} is not valid in an identifier", span.text());
+                return Err(error(&span, &msg));
+            }
+            return Ok(Some(c));
+        }
+
+        match self.peek_char() {
+            Some(c) if is_ident(c) => {
+                self.next_pos(c.len_utf8());
+                Ok(Some(c))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Universal character name: \uXXXX or \UXXXXXXXX
+    fn read_ucn(&mut self) -> Result<char, Diagnostic> {
+        let start = self.mark();
+        let len = if self.peek(1) == Some(b'u') { 4 } else { 8 };
+        self.next_pos(2);
+
+        let mut val: u32 = 0;
+        for _ in 0..len {
+            match self.peek(0).and_then(|b| (b as char).to_digit(16)) {
+                Some(d) => {
+                    val = val.saturating_mul(16).saturating_add(d);
+                    self.next_pos(1);
+                }
+                None => return Err(error(&self.span_from(start), "incomplete universal character name")),
+            }
+        }
+
+        let span = self.span_from(start);
+        char::from_u32(val)
+            .ok_or_else(|| error(&span, &format!("{} is not a valid universal character", span.text())))
+    }
+
+    // 識別子かキーワードを読む
+    fn read_ident(&mut self) -> Result<Option<TokenKind>, Diagnostic> {
+        let start = self.mark();
+        // The name is the source text unless it contains UCNs, in which
+        // case it is built from the decoded characters.
+        let mut decoded: Option<String> = None;
+        let mut first = true;
+        loop {
+            let before = self.mark();
+            let is_ucn = self.peek(0) == Some(b'\\');
+            let c = match self.read_ident_char(first)? {
+                Some(c) => c,
+                None => break,
+            };
+            if is_ucn && decoded.is_none() {
+                decoded = Some(Span { end: before.start, ..start.clone() }.text().into_owned());
+            }
+            if let Some(name) = &mut decoded {
+                name.push(c);
+            }
+            first = false;
+        }
+        if first {
+            return Ok(None);
+        }
+
+        let span = self.span_from(start);
+        let name = match &decoded {
+            Some(name) => Cow::Borrowed(name.as_str()),
+            None => span.text(),
         };
-        Token::new(kind)
-    }
-
-    fn convert_keyword(self) -> Self {
-        let ident = retrieve_ident!(self);
-        let kind = match ident.as_str() {
-            "int"    => TokenKind::Keyword(Keyword::Int),
-            "if"     => TokenKind::Keyword(Keyword::If),
-            "else"   => TokenKind::Keyword(Keyword::Else),
-            "for"    => TokenKind::Keyword(Keyword::For),
-            "while"  => TokenKind::Keyword(Keyword::While),
-            "return" => TokenKind::Keyword(Keyword::Return),
-                   _ => return self 
+        match keyword(&name) {
+            Some(kw) => Ok(Some(TokenKind::Keyword(kw))),
+            None => Ok(Some(TokenKind::Ident(self.names.intern(&name)))),
+        }
+    }
+
+    // 区切り記号を読む。長いものから順に試す
+    fn read_punct(&mut self) -> Option<Symbol> {
+        let (sym, len) = match (self.peek(0)?, self.peek(1), self.peek(2)) {
+            (b'<', Some(b'<'), Some(b'=')) => (Symbol::ShlAssign, 3),
+            (b'>', Some(b'>'), Some(b'=')) => (Symbol::ShrAssign, 3),
+            (b'.', Some(b'.'), Some(b'.')) => (Symbol::Ellipsis, 3),
+
+            (b'=', Some(b'='), _) => (Symbol::Eq, 2),
+            (b'!', Some(b'='), _) => (Symbol::Ne, 2),
+            (b'<', Some(b'='), _) => (Symbol::Le, 2),
+            (b'>', Some(b'='), _) => (Symbol::Ge, 2),
+            (b'&', Some(b'&'), _) => (Symbol::LogAnd, 2),
+            (b'|', Some(b'|'), _) => (Symbol::LogOr, 2),
+            (b'<', Some(b'<'), _) => (Symbol::Shl, 2),
+            (b'>', Some(b'>'), _) => (Symbol::Shr, 2),
+            (b'+', Some(b'+'), _) => (Symbol::Inc, 2),
+            (b'-', Some(b'-'), _) => (Symbol::Dec, 2),
+            (b'+', Some(b'='), _) => (Symbol::AddAssign, 2),
+            (b'-', Some(b'='), _) => (Symbol::SubAssign, 2),
+            (b'*', Some(b'='), _) => (Symbol::MulAssign, 2),
+            (b'/', Some(b'='), _) => (Symbol::DivAssign, 2),
+            (b'%', Some(b'='), _) => (Symbol::ModAssign, 2),
+            (b'&', Some(b'='), _) => (Symbol::AndAssign, 2),
+            (b'|', Some(b'='), _) => (Symbol::OrAssign, 2),
+            (b'^', Some(b'='), _) => (Symbol::XorAssign, 2),
+            (b'#', Some(b'#'), _) => (Symbol::HashHash, 2),
+            (b'-', Some(b'>'), _) => (Symbol::Arrow, 2),
+
+            (b'+', ..) => (Symbol::Add, 1),
+            (b'-', ..) => (Symbol::Sub, 1),
+            (b'*', ..) => (Symbol::Asterisk, 1),
+            (b'/', ..) => (Symbol::Div, 1),
+            (b'%', ..) => (Symbol::Percent, 1),
+            (b'<', ..) => (Symbol::Lt, 1),
+            (b'>', ..) => (Symbol::Gt, 1),
+            (b'!', ..) => (Symbol::Not, 1),
+            (b'=', ..) => (Symbol::Assign, 1),
+            (b'&', ..) => (Symbol::Ampersand, 1),
+            (b'|', ..) => (Symbol::Pipe, 1),
+            (b'^', ..) => (Symbol::Caret, 1),
+            (b'~', ..) => (Symbol::Tilde, 1),
+            (b',', ..) => (Symbol::Comma, 1),
+            (b'?', ..) => (Symbol::Question, 1),
+            (b':', ..) => (Symbol::Colon, 1),
+            (b';', ..) => (Symbol::Semicolon, 1),
+            (b'(', ..) => (Symbol::OpeningParen, 1),
+            (b')', ..) => (Symbol::ClosingParen, 1),
+            (b'{', ..) => (Symbol::OpeningBrace, 1),
+            (b'}', ..) => (Symbol::ClosingBrace, 1),
+            (b'[', ..) => (Symbol::OpeningBracket, 1),
+            (b']', ..) => (Symbol::ClosingBracket, 1),
+            (b'.', ..) => (Symbol::Dot, 1),
+            (b'#', ..) => (Symbol::Hash, 1),
+            _ => return None,
         };
-        Token::new(kind)
+        self.next_pos(len);
+        Some(sym)
     }
 
-    fn convert_reserved(self) -> Self {
-        let mut token = self.convert_symbol();
-        token = token.convert_keyword();
-        token
+    // 'a'のような文字定数を読む。型はintで、値はcharとして符号拡張する
+    fn read_char_literal(&mut self) -> Result<i64, Diagnostic> {
+        let start = self.mark();
+        self.next_pos(1);
+
+        let c = match self.peek_char() {
+            Some('\n') | None => return Err(error(&self.span_from(start), "unclosed char literal")),
+            Some('\'') => {
+                self.next_pos(1);
+                return Err(error(&self.span_from(start), "empty character constant"));
+            }
+            Some(c) => c,
+        };
+
+        self.next_pos(c.len_utf8());
+        let val = if c == '\\' { self.read_escaped_char()? } else { c as u32 };
+
+        if self.peek(0) != Some(b'\'') {
+            // Find the closing quote to underline the whole literal.
+            while let Some(b) = self.peek(0) {
+                if b == b'\'' || b == b'\n' { break; }
+                self.next_pos(1);
+            }
+            if self.peek(0) == Some(b'\'') {
+                self.next_pos(1);
+                return Err(error(&self.span_from(start), "multi-character character constant"));
+            }
+            return Err(error(&self.span_from(start), "unclosed char literal"));
+        }
+        self.next_pos(1);
+
+        // A char holds one byte, but a non-ASCII character takes several
+        // in UTF-8.
+        if c != '\\' && !c.is_ascii() {
+            return Err(error(&self.span_from(start), "multi-character character constant")
+                .with_note(format!("'{}' is {} bytes in UTF-8", c, c.len_utf8())));
+        }
+
+        Ok(val as u8 as i8 as i64)
     }
 
-    pub fn get_string(&self) -> String {
-        match &self.kind {
-            TokenKind::Ident(s) => s.clone(),
-            TokenKind::Num(n) => n.to_string(),
-            _ => format!("error:{:#?}", self),
+    // "..."のような文字列リテラルを読む
+    fn read_string_literal(&mut self) -> Result<Vec<u8>, Diagnostic> {
+        let start = self.mark();
+        self.next_pos(1);
+
+        let mut buf = vec![];
+        loop {
+            let b = match self.peek(0) {
+                Some(b'\n') | None => return Err(error(&self.span_from(start), "unclosed string literal")),
+                Some(b) => b,
+            };
+            self.next_pos(1);
+
+            match b {
+                b'"' => break,
+                b'\\' => buf.push(self.read_escaped_char()? as u8),
+                _ => buf.push(b),
+            }
+        }
+        Ok(buf)
+    }
+
+    // 0x1fULのような整数リテラルを読み、値と型を返す。
+    // 値がunsigned longの場合でもビットパターンはそのままi64に入れる
+    fn read_int_literal(&mut self) -> Result<(i64, IntType), Diagnostic> {
+        let start = self.mark();
+
+        // Read the whole literal including the suffix, e.g. "0x1fUL".
+        while let Some(c) = self.peek_char() {
+            if !is_ident2(c) { break; }
+            self.next_pos(c.len_utf8());
+        }
+        let span = self.span_from(start);
+        let s = span.text();
+
+        let lower = s.to_ascii_lowercase();
+        let (base, digits_at) = if lower.starts_with("0x") {
+            (16, 2)
+        } else if lower.starts_with("0b") {
+            (2, 2)
+        } else if s.starts_with('0') {
+            (8, 0)
+        } else {
+            (10, 0)
+        };
+
+        let rest = &s[digits_at..];
+        let ndigits = rest.find(|c: char| !c.is_digit(base.max(10))).unwrap_or(rest.len());
+        let (digits, suffix) = rest.split_at(ndigits);
+
+        if digits.is_empty() && base != 8 {
+            return Err(error(&span, &format!("invalid integer literal '{}'", s))
+                .with_note("expected digits after the base prefix"));
+        }
+
+        let mut val: u64 = 0;
+        for c in digits.chars() {
+            let d = c.to_digit(10.max(base)).unwrap();
+            if d >= base {
+                let kind = if base == 8 { "octal" } else { "binary" };
+                return Err(error(&span, &format!("invalid digit '{}' in {} constant", c, kind)));
+            }
+            val = match val.checked_mul(base as u64).and_then(|v| v.checked_add(d as u64)) {
+                Some(v) => v,
+                None => {
+                    return Err(Diagnostic::error("integer literal is too large")
+                        .with_primary(&span, "")
+                        .with_note(format!("the largest value is {}", u64::MAX)));
+                }
+            };
+        }
+
+        // "ll" must not mix cases, e.g. "lL".
+        let suffix_error = || error(&span, &format!("invalid suffix '{}' on integer constant", suffix));
+        if suffix.contains("lL") || suffix.contains("Ll") {
+            return Err(suffix_error());
+        }
+        // Number of 'l's in the suffix
+        let (unsigned, longs) = match suffix.to_ascii_lowercase().as_str() {
+            "" => (false, 0),
+            "u" => (true, 0),
+            "l" => (false, 1),
+            "ll" => (false, 2),
+            "ul" | "lu" => (true, 1),
+            "ull" | "llu" => (true, 2),
+            _ => return Err(suffix_error()),
+        };
+
+        // The type is the first one in the list that can represent the
+        // value. Octal, hex and binary literals may also be unsigned.
+        // long long is never needed without "ll" because it is no wider
+        // than long.
+        let candidates: &[IntType] = match (unsigned, longs, base == 10) {
+            (false, 0, true)  => &[IntType::Int, IntType::Long],
+            (false, 0, false) => &[IntType::Int, IntType::UInt, IntType::Long, IntType::ULong],
+            (false, 1, true)  => &[IntType::Long],
+            (false, 1, false) => &[IntType::Long, IntType::ULong],
+            (false, _, true)  => &[IntType::LongLong],
+            (false, _, false) => &[IntType::LongLong, IntType::ULongLong],
+            (true, 0, _)      => &[IntType::UInt, IntType::ULong],
+            (true, 1, _)      => &[IntType::ULong],
+            (true, _, _)      => &[IntType::ULongLong],
+        };
+
+        for ty in candidates {
+            let max = match ty {
+                IntType::Int => i32::MAX as u64,
+                IntType::UInt => u32::MAX as u64,
+                IntType::Long | IntType::LongLong => i64::MAX as u64,
+                IntType::ULong | IntType::ULongLong => u64::MAX,
+            };
+            if val <= max {
+                return Ok((val as i64, *ty));
+            }
+        }
+
+        Err(Diagnostic::error("integer literal is too large to be represented in a signed integer type")
+            .with_primary(&span, "")
+            .with_note(format!("the largest value is {}", i64::MAX))
+            .with_help("add a 'u' suffix to make it unsigned"))
+    }
+}
+
+impl Token {
+    // Location to report the token at. Tokens created by "##" or builtin
+    // macros have no source text of their own, so the macro invocation is
+    // used instead.
+    pub fn loc(&self) -> &Span {
+        match &self.origin {
+            Some(origin) if self.span.file.is_scratch() => origin,
+            _ => &self.span,
+        }
+    }
+
+    pub fn get_ident(&self) -> Name {
+        match self.kind {
+            TokenKind::Ident(name) => name,
+            _ => panic!()
         }
     }
 
     pub fn get_num(&self) -> i64 {
         match self.kind {
-            TokenKind::Num(n) => n,
+            TokenKind::Num(n, _) => n,
             _ => panic!()
         }
     }
 
 }
 
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Symbol::Add          => "+",
+            Symbol::Sub          => "-",
+            Symbol::Div          => "/",
+            Symbol::Percent      => "%",
+            Symbol::Shl          => "<<",
+            Symbol::Shr          => ">>",
+            Symbol::Eq           => "==",
+            Symbol::Ne           => "!=",
+            Symbol::Lt           => "<",
+            Symbol::Le           => "<=",
+            Symbol::Gt           => ">",
+            Symbol::Ge           => ">=",
+            Symbol::LogAnd       => "&&",
+            Symbol::LogOr        => "||",
+            Symbol::Not          => "!",
+            Symbol::Assign       => "=",
+            Symbol::AddAssign    => "+=",
+            Symbol::SubAssign    => "-=",
+            Symbol::MulAssign    => "*=",
+            Symbol::DivAssign    => "/=",
+            Symbol::ModAssign    => "%=",
+            Symbol::AndAssign    => "&=",
+            Symbol::OrAssign     => "|=",
+            Symbol::XorAssign    => "^=",
+            Symbol::ShlAssign    => "<<=",
+            Symbol::ShrAssign    => ">>=",
+            Symbol::Inc          => "++",
+            Symbol::Dec          => "--",
+            Symbol::Ampersand    => "&",
+            Symbol::Pipe         => "|",
+            Symbol::Caret        => "^",
+            Symbol::Tilde        => "~",
+            Symbol::Asterisk     => "*",
+            Symbol::Comma        => ",",
+            Symbol::Question     => "?",
+            Symbol::Colon        => ":",
+            Symbol::Semicolon    => ";",
+            Symbol::OpeningParen => "(",
+            Symbol::ClosingParen => ")",
+            Symbol::OpeningBrace => "{",
+            Symbol::ClosingBrace => "}",
+            Symbol::OpeningBracket => "[",
+            Symbol::ClosingBracket => "]",
+            Symbol::Dot          => ".",
+            Symbol::Arrow        => "->",
+            Symbol::Hash         => "#",
+            Symbol::HashHash     => "##",
+            Symbol::Ellipsis     => "...",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Keyword {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Keyword::Char   => "char",
+            Keyword::Short  => "short",
+            Keyword::Int    => "int",
+            Keyword::Long   => "long",
+            Keyword::If     => "if",
+            Keyword::Else   => "else",
+            Keyword::For    => "for",
+            Keyword::While  => "while",
+            Keyword::Return => "return",
+        }
+    }
+}
+
+impl fmt::Display for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenKind::Keyword(k) => write!(f, "'{}'", k),
+            TokenKind::Symbol(s) => write!(f, "'{}'", s),
+            TokenKind::Ident(_) => write!(f, "identifier"),
+            TokenKind::Num(n, _) => write!(f, "'{}'", n),
+            TokenKind::Str(s) => write!(f, "\"{}\"", s.escape_ascii()),
+            TokenKind::Invalid(_) => write!(f, "invalid token"),
+            TokenKind::Eof => write!(f, "end of file"),
+        }
+    }
+}
+
+// Identifiers are spelled as written, which TokenKind alone can't do.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            TokenKind::Ident(_) => write!(f, "'{}'", self.span.text()),
+            _ => write!(f, "{}", self.kind),
+        }
+    }
+}
+
 impl TokenKind {
     pub fn is_identifier(&self) -> bool {
         matches!(self, TokenKind::Ident(_))
@@ -186,130 +845,160 @@ impl TokenKind {
     }
 
     pub fn is_num(&self) -> bool {
-        matches!(self, TokenKind::Num(_))
+        matches!(self, TokenKind::Num(..))
     }
 
-}
-
-fn ispunct(c: &char) -> bool {
-    // punct of C
-    let mut punct = [
-        '!', '"', '#', '$', '%', '&', '\'', '(', ')', '*', '+', ',', '-',
-        '.', '/', ':', ';', '<', '=', '>', '?', '@', '[', '\\', ']', '^',
-        '_', '`', '{', '|', '}'
-    ].iter();
-
-    match punct.find(|x| x == &c) {
-        Some(_) => true,
-        None => false,
+    pub fn is_str(&self) -> bool {
+        matches!(self, TokenKind::Str(_))
     }
+
 }
 
-fn is_alpha(c: &char) -> bool {
-    return ('a' <= *c && *c <= 'z') || ('A' <= *c && *c <= 'Z') || *c == '_';
+fn keyword(name: &str) -> Option<Keyword> {
+    let kw = match name {
+        "char"   => Keyword::Char,
+        "short"  => Keyword::Short,
+        "int"    => Keyword::Int,
+        "long"   => Keyword::Long,
+        "if"     => Keyword::If,
+        "else"   => Keyword::Else,
+        "for"    => Keyword::For,
+        "while"  => Keyword::While,
+        "return" => Keyword::Return,
+               _ => return None,
+    };
+    Some(kw)
 }
 
-fn is_alnum(c: &char) -> bool {
-    return is_alpha(&c) || ('0' <= *c && *c <= '9');
+fn error(span: &Span, msg: &str) -> Diagnostic {
+    Diagnostic::error(msg).with_primary(span, "")
 }
 
-fn startswith(vc: &[char], s: &str) -> bool {
-    for i in 0..s.len() {
-        if vc.get(i).unwrap() != &s.chars().nth(i).unwrap() {
-            return false;
+impl Lexer<'_> {
+    // トークンを1つ読む
+    fn read_token(&mut self, b: u8, start: &Span) -> Result<TokenKind, Diagnostic> {
+        // Character constant
+        if b == b'\'' {
+            let val = self.read_char_literal()?;
+            return Ok(TokenKind::Num(val, IntType::Int));
         }
-    }
-    true
-}
 
-fn starts_with_reserved(vc: &[char]) -> Option<String> {
-    // Keyword
-    let kw = ["return", "if", "else", "for", "while", "int"];
+        // String literal
+        if b == b'"' {
+            return Ok(TokenKind::Str(self.read_string_literal()?));
+        }
 
-    for i in 0..kw.len() {
-        let len = kw[i].len();
-        if startswith(vc, kw[i]) && !is_alnum(&vc[len]) {
-            return Some(kw[i].to_string());
+        // Numeric literal
+        if b.is_ascii_digit() {
+            let (val, ty) = self.read_int_literal()?;
+            return Ok(TokenKind::Num(val, ty));
         }
-    }
 
-    // Multi-letter punctuators
-    let ops = ["==", "!=", "<=", ">="];
-    for i in 0..ops.len() {
-        if startswith(vc, ops[i]) {
-            return Some(ops[i].to_string());
+        // Identifier or keyword
+        if let Some(kind) = self.read_ident()? {
+            return Ok(kind);
         }
-    }
 
-    None
-}
+        // Punctuator
+        if let Some(sym) = self.read_punct() {
+            return Ok(TokenKind::Symbol(sym));
+        }
 
-pub fn error(s: String) {
-    eprintln!("{}", s);
-    process::exit(1);
-}
+        let c = self.peek_char().unwrap_or('\u{fffd}');
+        self.next_pos(c.len_utf8());
+        Err(error(&self.span_from(start.clone()), &format!("invalid character '{}'", c)))
+    }
+
+    // 不正な文字定数や文字列リテラルの残りを閉じ引用符か行末まで読み飛ばす
+    fn skip_literal(&mut self, quote: u8, start: &Span) {
+        if quote != b'\'' && quote != b'"' {
+            return;
+        }
+        // The closing quote may have been read already.
+        let bytes = self.file.contents.as_bytes();
+        if self.pos > start.start + 1 && bytes[self.pos - 1] == quote {
+            return;
+        }
+        loop {
+            match self.peek(0) {
+                Some(b'\n') | None => return,
+                Some(b) => {
+                    self.next_pos(1);
+                    if b == quote {
+                        return;
+                    }
+                    if b == b'\\' {
+                        self.next_pos(1);
+                    }
+                }
+            }
+        }
+    }
 
-impl Lexer {
-    pub fn tokenize(mut self) -> Vec<Token> {
+    pub fn tokenize(mut self) -> Result<Vec<Token>, Error> {
         let mut tokens = Vec::new();
-        while !self.is_last() {
-            let mut c = self.getc();
+        loop {
+            self.skip_splices();
+            let b = match self.peek(0) {
+                Some(b) => b,
+                None => break,
+            };
+
             // Skip whitespace characters.
-            if c.is_whitespace() {
+            if matches!(b, b' ' | b'\t' | b'\n' | b'\r' | 0x0b | 0x0c) {
+                if b == b'\n' {
+                    self.at_bol = true;
+                }
+                self.has_space = true;
                 self.next_pos(1);
                 continue;
             }
 
-            // Numeric literal
-            if c.is_ascii_digit() {
-                let val = self.strtol();
-                let token = Token::new(TokenKind::Num(val));
-                tokens.push(token);
-                continue;
-            }
-
-            // Keywords or Multi-letter punctuators
-            if let Some(s) = starts_with_reserved(&self.code[self.pos..]) {
-                let len = s.len();
-                let token = Token::new(TokenKind::Ident(s));
-                self.next_pos(len);
-                tokens.push(token);
-                continue;
-            }
+            let start = self.mark();
 
-            // Identifier
-            if is_alpha(c) {
-                let mut s = String::new();
-                while is_alnum(c) {
-                    s.push_str(&c.to_string());
+            // Skip line comments.
+            if b == b'/' && self.peek(1) == Some(b'/') {
+                while !matches!(self.peek(0), Some(b'\n') | None) {
                     self.next_pos(1);
-                    c = self.getc();
                 }
-
-                let token = Token::new(TokenKind::Ident(s));
-
-                tokens.push(token);
+                self.has_space = true;
                 continue;
             }
 
-            // Punctuator
-            if ispunct(c) {
-                let token = Token::new(TokenKind::Ident(c.to_string()));
-                self.next_pos(1);
-                tokens.push(token);
+            // Skip block comments.
+            if b == b'/' && self.peek(1) == Some(b'*') {
+                self.next_pos(2);
+                let open = self.span_from(start);
+                loop {
+                    match (self.peek(0), self.peek(1)) {
+                        (Some(b'*'), Some(b'/')) => break,
+                        (Some(_), _) => self.next_pos(1),
+                        (None, _) => {
+                            return Err(Diagnostic::error("unterminated block comment")
+                                .with_primary(&open, "comment starts here")
+                                .into());
+                        }
+                    }
+                }
+                self.next_pos(2);
+                self.has_space = true;
                 continue;
             }
 
-            error(format!("invalid token: {}", c));
-        }
-
-        tokens.push(Token::new(TokenKind::Eof));
-
-        let mut tokens2 = Vec::new();
-        for t in tokens {
-            tokens2.push(t.convert_reserved());
+            // A malformed token is an error only if it survives
+            // preprocessing. It may be in a skipped #if group.
+            let kind = match self.read_token(b, &start) {
+                Ok(kind) => kind,
+                Err(diag) => {
+                    self.skip_literal(b, &start);
+                    TokenKind::Invalid(Box::new(diag))
+                }
+            };
+            tokens.push(self.new_token(kind, start));
         }
 
-        return tokens2;
+        let eof = self.new_token(TokenKind::Eof, self.mark());
+        tokens.push(eof);
+        Ok(tokens)
     }
 }
@@ -1,25 +1,32 @@
 use super::parse::{ Node, NodeKind::* };
 use super::tokenize::Token;
+use std::cmp::Ordering;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Default, PartialEq, Clone)]
 pub enum TypeKind {
+    Char,
+    Short,
     Int,
+    Long,
+    LongLong,
     Ptr,
     Func,
+    Array,
+    #[default]
     _None,   // Default
 }
 
-impl Default for TypeKind { 
-    fn default() -> Self { TypeKind::_None }
-}
-
 #[derive(Debug, Default, PartialEq, Clone)]
 pub struct Type {
     pub kind: TypeKind,
+    pub is_unsigned: bool,
 
-    // Pointer
+    // Pointer or array
     pub base: Option<Box<Type>>,
 
+    // Array
+    pub array_len: usize,
+
     // Declaration
     pub name: Option<Token>,
 
@@ -29,7 +36,40 @@ pub struct Type {
 }
 
 pub fn is_integer(ty: &Type) -> bool {
-    ty.kind == TypeKind::Int
+    matches!(ty.kind, TypeKind::Char | TypeKind::Short | TypeKind::Int | TypeKind::Long | TypeKind::LongLong)
+}
+
+// Sizes follow the LP64 data model of x86-64 Linux.
+pub fn size_of(ty: &Type) -> usize {
+    match ty.kind {
+        TypeKind::Char | TypeKind::Func => 1,
+        TypeKind::Short => 2,
+        TypeKind::Int => 4,
+        TypeKind::Array => size_of(ty.base.as_ref().unwrap()) * ty.array_len,
+        _ => 8,
+    }
+}
+
+pub fn align_of(ty: &Type) -> usize {
+    match ty.kind {
+        TypeKind::Array => align_of(ty.base.as_ref().unwrap()),
+        _ => size_of(ty),
+    }
+}
+
+// True if values of the two types can be mixed, e.g. the targets of
+// two pointers subtracted from each other.
+pub fn is_compatible(t1: &Type, t2: &Type) -> bool {
+    if t1.kind != t2.kind || t1.is_unsigned != t2.is_unsigned {
+        return false;
+    }
+    match t1.kind {
+        TypeKind::Ptr => is_compatible(t1.base.as_ref().unwrap(), t2.base.as_ref().unwrap()),
+        TypeKind::Array => {
+            t1.array_len == t2.array_len && is_compatible(t1.base.as_ref().unwrap(), t2.base.as_ref().unwrap())
+        }
+        _ => true,
+    }
 }
 
 pub fn pointer_to(base: Type) -> Type {
@@ -40,6 +80,29 @@ pub fn pointer_to(base: Type) -> Type {
     }
 }
 
+pub fn array_of(base: Type, len: usize) -> Type {
+    Type {
+        kind: TypeKind::Array,
+        base: Some(Box::new(base)),
+        array_len: len,
+        ..Default::default()
+    }
+}
+
+pub fn ty_char() -> Type {
+    Type {
+        kind: TypeKind::Char,
+        ..Default::default()
+    }
+}
+
+pub fn ty_short() -> Type {
+    Type {
+        kind: TypeKind::Short,
+        ..Default::default()
+    }
+}
+
 pub fn ty_int() -> Type {
     Type {
         kind: TypeKind::Int,
@@ -47,6 +110,59 @@ pub fn ty_int() -> Type {
     }
 }
 
+pub fn ty_long() -> Type {
+    Type {
+        kind: TypeKind::Long,
+        ..Default::default()
+    }
+}
+
+pub fn ty_long_long() -> Type {
+    Type {
+        kind: TypeKind::LongLong,
+        ..Default::default()
+    }
+}
+
+// Integer conversion rank. Types ranked below int are promoted to int.
+fn rank(ty: &Type) -> i32 {
+    match ty.kind {
+        TypeKind::Char | TypeKind::Short => 0,
+        TypeKind::Int => 1,
+        TypeKind::LongLong => 3,
+        _ => 2,
+    }
+}
+
+// The usual arithmetic conversions: the type both operands of a
+// binary operator are converted to.
+pub fn get_common_type(ty1: &Type, ty2: &Type) -> Type {
+    if let Some(base) = &ty1.base {
+        return pointer_to(*base.clone());
+    }
+
+    // Integer promotion
+    let (r1, r2) = (rank(ty1).max(1), rank(ty2).max(1));
+    let u1 = ty1.is_unsigned && rank(ty1) >= 1;
+    let u2 = ty2.is_unsigned && rank(ty2) >= 1;
+
+    // long can represent every unsigned int value, so the signedness
+    // comes from the operand with the greater rank. long long can't
+    // represent every unsigned long value because both are 8 bytes.
+    let is_unsigned = match r1.cmp(&r2) {
+        Ordering::Equal => u1 || u2,
+        Ordering::Greater => u1 || (u2 && r2 > 1),
+        Ordering::Less => u2 || (u1 && r1 > 1),
+    };
+
+    let ty = match r1.max(r2) {
+        1 => ty_int(),
+        2 => ty_long(),
+        _ => ty_long_long(),
+    };
+    Type { is_unsigned, ..ty }
+}
+
 pub fn func_type(return_ty: Type) -> Type {
     Type {
         kind: TypeKind::Func,
@@ -56,8 +172,7 @@ pub fn func_type(return_ty: Type) -> Type {
 }
 
 pub fn copy_type(ty: Type) -> Type {
-    let ret = ty.clone();
-    ret
+    ty
 }
 
 pub fn add_type(node: &mut Node) -> Node {
@@ -69,24 +184,56 @@ pub fn add_type(node: &mut Node) -> Node {
     if let Some(n) = &node.els { node.els = Some(Box::new(add_type(&mut n.as_ref().clone()))); }
     if let Some(n) = &node.init { node.init = Some(Box::new(add_type(&mut n.as_ref().clone()))); }
     if let Some(n) = &node.inc { node.inc = Some(Box::new(add_type(&mut n.as_ref().clone()))); }
+    if let Some(body) = &mut node.body {
+        for n in body.iter_mut() { **n = add_type(n); }
+    }
 
     match node.kind {
-        Add | Sub | Mul | Div | Assign => {
+        Add | Sub | Mul | Div | Mod | BitAnd | BitOr | BitXor => {
+            let lhs = &node.lhs.as_ref().unwrap().ty;
+            let rhs = &node.rhs.as_ref().unwrap().ty;
+            node.ty = if node.kind == Sub && lhs.base.is_some() && rhs.base.is_some() {
+                // ptr - ptr
+                ty_long()
+            } else {
+                get_common_type(lhs, rhs)
+            }
+        }
+        // The result has the promoted type of the left operand.
+        Shl | Shr | BitNot => {
+            node.ty = get_common_type(&node.lhs.as_ref().unwrap().ty, &ty_int())
+        }
+        Assign => {
             node.ty = node.lhs.as_ref().unwrap().ty.clone()
         }
-        Equal | Ne | Lt | Le | Var | Num | Funcall => {
+        Comma => {
+            node.ty = node.rhs.as_ref().unwrap().ty.clone()
+        }
+        Cond => {
+            let then = &node.then.as_ref().unwrap().ty;
+            let els = &node.els.as_ref().unwrap().ty;
+            node.ty = if then.base.is_none() && els.base.is_some() {
+                get_common_type(els, then)
+            } else {
+                get_common_type(then, els)
+            }
+        }
+        // Numeric literals, variables and calls are typed by the parser.
+        Num | Var | Funcall if node.ty.kind != TypeKind::_None => {}
+        Equal | Ne | Lt | Le | LogAnd | LogOr | Not | Var | Num | Funcall => {
             node.ty = ty_int()
         }
+        // &array is a pointer to the whole array. It decays to a pointer
+        // to the first element only where it is used as a value.
         Addr => node.ty = pointer_to(node.lhs.as_ref().unwrap().ty.clone()),
         Deref => {
-            if node.lhs.as_ref().unwrap().ty.kind == TypeKind::Ptr {
-                node.ty = *node.lhs.as_ref().unwrap().ty.base.as_ref().unwrap().clone()
-            } else {
-                node.ty = ty_int()
+            node.ty = match &node.lhs.as_ref().unwrap().ty.base {
+                Some(base) => *base.clone(),
+                None => ty_int(),
             }
         }
-        _ =>  {} 
+        _ =>  {}
     }
 
-    return node.clone();
+    node.clone()
 }
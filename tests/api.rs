@@ -0,0 +1,74 @@
+// Errors from the library are returned to the caller, never printed or
+// turned into a process exit.
+
+extern crate toycc;
+
+use toycc::codegen::codegen;
+use toycc::diagnostic::{ Diagnostic, Error };
+use toycc::intern::Interner;
+use toycc::parse::{ parse, Program };
+use toycc::preprocess::{ preprocess, PreprocessOptions };
+use toycc::tokenize::{ Lexer, SourceFile };
+
+fn front(src: &str, names: &mut Interner) -> Result<Program, Error> {
+    let file = SourceFile::new("test.c", src.to_string());
+    let tokens = Lexer::new(file, names).tokenize()?;
+    let tokens = preprocess(tokens, &PreprocessOptions::default(), names, &mut vec![])?;
+    parse(tokens, names)
+}
+
+fn first_error(res: Result<impl Sized, Error>) -> Diagnostic {
+    match res {
+        Ok(_) => panic!("expected an error"),
+        Err(e) => e.diagnostics.into_iter().next().unwrap(),
+    }
+}
+
+// Line and column of the primary label
+fn location(diag: &Diagnostic) -> (usize, usize) {
+    let span = &diag.primary.as_ref().unwrap().span;
+    (span.line, span.col)
+}
+
+#[test]
+fn tokenize_error() {
+    let mut names = Interner::new();
+    let file = SourceFile::new("test.c", "int x;\n/* never closed".to_string());
+    let diag = first_error(Lexer::new(file, &mut names).tokenize());
+    assert_eq!(diag.message, "unterminated block comment");
+    assert_eq!(location(&diag), (2, 1));
+}
+
+#[test]
+fn preprocess_error() {
+    let mut names = Interner::new();
+    let diag = first_error(front("#if 1\nint x;\n", &mut names));
+    assert_eq!(diag.message, "unterminated #if");
+}
+
+#[test]
+fn parse_error() {
+    let mut names = Interner::new();
+    let diag = first_error(front("int main() {\n  return 1\n}\n", &mut names));
+    assert_eq!(diag.message, "expected ';', found '}'");
+    assert_eq!(location(&diag), (3, 1));
+
+    let rendered = diag.to_string();
+    assert!(rendered.starts_with("test.c:3:1: error: "), "{}", rendered);
+    assert!(rendered.contains(" 3 | }\n"), "{}", rendered);
+}
+
+// codegen keeps its register state in statics, so all of its cases are
+// run from a single test.
+#[test]
+fn codegen_result() {
+    let mut names = Interner::new();
+    let prog = front("int f(int a, int b, int c, int d, int e, int g, int h) { return h; }", &mut names).unwrap();
+    let diag = first_error(codegen(prog, &names));
+    assert_eq!(diag.message, "too many arguments");
+    assert_eq!(location(&diag), (1, 53));
+
+    let prog = front("int main() { return 42; }", &mut names).unwrap();
+    let asm = codegen(prog, &names).unwrap();
+    assert!(asm.contains("main:"), "{}", asm);
+}
@@ -12,17 +12,37 @@ int sub(int x, int y) { return x - y; }
 int add6(int a, int b, int c, int d, int e, int f) {
     return a+b+c+d+e+f;
 }
+
+int char_at(char *s, int i) { return s[i]; }
+int str_len(char *s) { int n = 0; while (s[n]) n++; return n; }
+int neg(int x) { return -x; }
+int set_int(int *p, int v) { *p = v; return 0; }
+int sum_ints(int *a, int n) { int s = 0; for (int i = 0; i < n; i++) s += a[i]; return s; }
+long add_long(long a, long b) { return a + b; }
+short set_short(short *p, short v) { *p = v; return 0; }
 EOF
 
+mkdir -p ./target/include/sub
+echo 'int ret7() { return 7; }' > ./target/include/ret7.h
+printf '#pragma once\nint once() { return 1; }\n' > ./target/include/once.h
+printf '#include "sub/inner.h"\n#define NESTED INNER + 1\n' > ./target/include/nested.h
+echo '#define INNER 41' > ./target/include/sub/inner.h
+printf '#line 1 "elsewhere/fake.h"\n#include "sub/inner.h"\n' > ./target/include/lined.h
+printf '#line 10 "fake_once.h"\n#pragma once\nint lined_once() { return __LINE__; }\n' > ./target/include/lined_once.h
+echo '#include "nope.h"' > ./target/include/missing.h
+printf '#ifndef GUARD_H\n#define GUARD_H\nint guard() { return 12; }\n#endif\n' > ./target/include/guard.h
+
 
 #!/bin/bash
+# Extra compiler flags can be given as the third argument.
 assert() {
   expected="$1"
   input="$2"
+  flags="$3"
 
-  ./target/debug/toycc "$input" > ./target/tmp.s || exit
-  gcc -static -o ./target/tmp ./target/tmp.s ./target/tmp2.o
-  ./target/tmp
+  printf '%s\n' "$input" | ./target/debug/toycc $flags - > ./target/tmp.s || exit
+  gcc -static -o ./target/tmp.out ./target/tmp.s ./target/tmp2.o
+  ./target/tmp.out
   actual="$?"
 
   if [ "$actual" = "$expected" ]; then
@@ -33,6 +53,46 @@ assert() {
   fi
 }
 
+# Preprocess with -E, then compile the output again.
+assert_preprocess() {
+  expected="$1"
+  input="$2"
+
+  printf '%s\n' "$input" | ./target/debug/toycc -E - > ./target/tmp.i || exit
+  ./target/debug/toycc ./target/tmp.i > ./target/tmp.s || exit
+  gcc -static -o ./target/tmp.out ./target/tmp.s ./target/tmp2.o
+  ./target/tmp.out
+  actual="$?"
+
+  if [ "$actual" = "$expected" ]; then
+    echo "$input => -E => $actual"
+  else
+    echo "$input => -E => $expected expected, but got $actual"
+    exit 1
+  fi
+}
+
+# Expect the compilation to fail with the given number of errors.
+assert_error() {
+  expected="$1"
+  input="$2"
+
+  printf '%s\n' "$input" | ./target/debug/toycc - > /dev/null 2> ./target/tmp.err
+  if [ "$?" = 0 ]; then
+    echo "$input => compilation error expected"
+    exit 1
+  fi
+  actual=$(grep -c ': error: ' ./target/tmp.err)
+
+  if [ "$actual" = "$expected" ]; then
+    echo "$input => $actual error(s)"
+  else
+    echo "$input => $expected error(s) expected, but got $actual"
+    cat ./target/tmp.err
+    exit 1
+  fi
+}
+
 assert 0 'int main() { return 0; }'
 assert 42 'int main() { return 42; }'
 assert 5 'int main() { return 2+3; }'
@@ -137,4 +197,605 @@ assert 55 'int main() { return fib(9); } int fib (int x) { if (x<=1) return 1; r
 
 assert 21 'int main() { return _add6(1,2,3,4,5,6); } int _add6(int a, int b, int c, int d, int e, int f) { return a+b+c+d+e+f; }'
 
+assert 2 'int main() { /* return 1; */ return 2; }'
+assert 2 'int main() { // return 1;
+return 2; }'
+assert 7 'int main() { int a=3; int b=4; return a/**/+b; }'
+assert 3 'int main() { int a=6; return a/*x*//2; }'
+assert 2 'int main() { return 2; } // trailing comment'
+
+assert 97 "int main() { return 'a'; }"
+assert 10 "int main() { return '\\n'; }"
+assert 9 "int main() { return '\\t'; }"
+assert 92 "int main() { return '\\\\'; }"
+assert 39 "int main() { return '\\''; }"
+assert 34 "int main() { return '\\\"'; }"
+assert 0 "int main() { return '\\0'; }"
+assert 65 "int main() { return '\\101'; }"
+assert 8 "int main() { return '\\10'; }"
+assert 127 "int main() { return '\\x7f'; }"
+assert 1 "int main() { return '\\xff' == -1; }"
+assert 1 "int main() { return '\\377' < 0; }"
+assert 27 "int main() { return '\\e'; }"
+assert 2 "int main() { int c='b'; return c-'a'+1; }"
+
+assert 0 'int main() { return str_len(""); }'
+assert 3 'int main() { return str_len("abc"); }'
+assert 97 'int main() { return char_at("abc", 0); }'
+assert 99 'int main() { return char_at("abc", 2); }'
+assert 0 'int main() { return char_at("abc", 3); }'
+assert 6 'int main() { return str_len("abc" "def"); }'
+assert 100 'int main() { return char_at("abc" "def", 3); }'
+assert 10 'int main() { return char_at("\n", 0); }'
+assert 34 'int main() { return char_at("\"", 0); }'
+assert 65 'int main() { return char_at("\x41\101", 1); }'
+assert 0 'int main() { return char_at("a\0b", 1); }'
+assert 3 'int main() { return str_len("a\tb"); }'
+assert 2 'int main() { return str_len("é"); }'
+assert 12 'int main() { return printf("hello, %s\n", "world") - 1; }'
+
+assert 16 'int main() { return 0x10; }'
+assert 31 'int main() { return 0X1f; }'
+assert 15 'int main() { return 017; }'
+assert 0 'int main() { return 0; }'
+assert 5 'int main() { return 0b101; }'
+assert 10 'int main() { return 10u; }'
+assert 3 'int main() { return 3L + 0ULL; }'
+assert 1 'int main() { long long x = 1LL << 40; return x == 1099511627776ll; }'
+assert 0 'int main() { return -1 < 0ULL; }'
+assert 0 'int main() { return 0xffffffffffffffffLL < 0; }'
+assert 1 'int main() { return 9223372036854775807LL > 0; }'
+assert 5 "#if 0
+char c = 'é';
+#endif
+int main() { return 5; }"
+assert 1 'int main() { return -1 < 0; }'
+assert 0 'int main() { return -1 < 0u; }'
+assert 1 'int main() { return 0xffffffffffffffff > 0; }'
+assert 1 'int main() { return -1 < 4294967295; }'
+assert 0 'int main() { return -1 < 4294967295u; }'
+assert 127 'int main() { return 0xffffffffffffffffu / 2 / 0x100000000000000; }'
+assert 1 'int main() { return -2 / 2 == -1; }'
+
+assert 1 'int main() { return 1 && 2; }'
+assert 0 'int main() { return 1 && 0; }'
+assert 0 'int main() { return 0 && 1; }'
+assert 1 'int main() { return 0 || 2; }'
+assert 0 'int main() { return 0 || 0; }'
+assert 1 'int main() { return 1 || 0 && 0; }'
+assert 0 'int main() { return !1; }'
+assert 1 'int main() { return !0; }'
+assert 1 'int main() { return !!5; }'
+assert 0 'int main() { int x=0; 0 && (x=1); return x; }'
+assert 0 'int main() { int x=0; 1 || (x=1); return x; }'
+assert 1 'int main() { int x=0; 1 && (x=1); return x; }'
+assert 3 'int main() { int x=3; int *p=&x; if (p && *p) return *p; return 0; }'
+
+assert 1 'int main() { return 10 % 3; }'
+assert 2 'int main() { return 17 % 5 % 3; }'
+assert 1 'int main() { return -7 % 3 == -1; }'
+assert 16 'int main() { return 1 << 4; }'
+assert 5 'int main() { return 20 >> 2; }'
+assert 1 'int main() { return -8 >> 1 == -4; }'
+assert 127 'int main() { return 0xffffffffffffffffu >> 57; }'
+assert 1 'int main() { return -1 >> 63 == -1; }'
+assert 2 'int main() { return 6 & 3; }'
+assert 7 'int main() { return 6 | 3; }'
+assert 5 'int main() { return 6 ^ 3; }'
+assert 1 'int main() { return ~0 == -1; }'
+assert 245 'int main() { return ~10 & 255; }'
+assert 1 'int main() { return 1 | 2 & 0; }'
+assert 3 'int main() { return 1 ^ 2 | 1; }'
+assert 0 'int main() { return 2 & 2 == 2; }'
+assert 8 'int main() { return 1 << 2 + 1; }'
+assert 1 'int main() { return 1 << 2 < 5; }'
+assert 1 'int main() { int x=3; return x & 1 && x & 2; }'
+
+assert 7 'int main() { int i=2; i+=5; return i; }'
+assert 7 'int main() { int i=2; return i+=5; }'
+assert 3 'int main() { int i=5; i-=2; return i; }'
+assert 6 'int main() { int i=3; i*=2; return i; }'
+assert 3 'int main() { int i=6; i/=2; return i; }'
+assert 2 'int main() { int i=7; i%=5; return i; }'
+assert 2 'int main() { int i=6; i&=3; return i; }'
+assert 7 'int main() { int i=6; i|=3; return i; }'
+assert 5 'int main() { int i=6; i^=3; return i; }'
+assert 16 'int main() { int i=1; i<<=4; return i; }'
+assert 2 'int main() { int i=16; i>>=3; return i; }'
+assert 3 'int main() { int i=2; return ++i; }'
+assert 1 'int main() { int i=2; return --i; }'
+assert 2 'int main() { int i=2; return i++; }'
+assert 2 'int main() { int i=2; return i--; }'
+assert 3 'int main() { int i=2; i++; return i; }'
+assert 1 'int main() { int i=2; i--; return i; }'
+assert 3 'int main() { int x=3; int y=5; int *p=&y; p++; return *p; }'
+assert 5 'int main() { int x=3; int y=5; int *p=&x; return *--p; }'
+assert 1 'int main() { char c=127; return c++==127; }'
+assert 1 'int main() { char c=-128; return c--==-128 && c==127; }'
+assert 1 'int main() { short s=32767; return s++==32767 && s==-32768; }'
+assert 1 'int main() { int i=2147483647; return i++==2147483647; }'
+assert 4 'int main() { int a[3]; a[1]=4; int *p=&a[1]; return *p++; }'
+assert 3 'int main() { int x=3; int y=5; int *p=&y; p+=1; return *p; }'
+assert 3 'int main() { int x=3; int y=5; int *p=&x; return *p++; }'
+assert 71 'int main() { int i=0; int x=5; *(&x + i++) += 2; return x*10 + i; }'
+assert 55 'int main() { int i; int j=0; for (i=0; i<=10; i++) j+=i; return j; }'
+assert 10 'int main() { int i=0; while (i<10) ++i; return i; }'
+
+assert 2 'int main() { return 1 ? 2 : 3; }'
+assert 3 'int main() { return 0 ? 2 : 3; }'
+assert 4 'int main() { return 0 ? 2 : 0 ? 3 : 4; }'
+assert 5 'int main() { int x=0; return x ? 1 : x+5; }'
+assert 1 'int main() { int x=0; 1 ? x=1 : (x=2); return x; }'
+assert 0 'int main() { return (1 ? -1 : 0u) < 0; }'
+assert 3 'int main() { int x=3; int y=5; int *p = 0 ? &y : &x; return *p; }'
+assert 3 'int main() { return (1, 2, 3); }'
+assert 5 'int main() { int i=2, j=3; i=(j=4, 5); return i; }'
+assert 10 'int main() { int i, j, k=0; for (i=0, j=10; i<j; i++, j--) k+=2; return k; }'
+
+assert 3 '#define N 3
+int main() { return N; }'
+assert 5 '#define ADD(a, b) ((a) + (b))
+int main() { return ADD(2, 3); }'
+assert 9 '#define SQ(x) ((x) * (x))
+int main() { return SQ(1 + 2); }'
+assert 2 '#define F(x) x
+int main() { int F = 2; return F; }'
+assert 4 '#define ONE 1
+#define TWO (ONE + ONE)
+int main() { return TWO + TWO; }'
+assert 3 '#define N 3
+#undef N
+int main() { int N = 3; return N; }'
+assert 1 'int main() { int x = 0;
+#define x x + 1
+return x; }'
+assert 11 'int f(int x) { return x + 10; }
+#define f(x) f(x)
+int main() { return f(1); }'
+assert 5 '#define STR(x) #x
+int main() { return str_len(STR(a + b)); }'
+assert 5 '#define STR(x) #x
+int main() { return str_len(STR("a\n")); }'
+assert 12 '#define CAT(a, b) a ## b
+int main() { int xy = 12; return CAT(x, y); }'
+assert 45 '#define CAT(a, b) a ## b
+int main() { return CAT(4, 5); }'
+assert 6 '#define CALL(f, ...) f(__VA_ARGS__)
+int add2(int a, int b) { return a + b; }
+int main() { return CALL(add2, 2, 4); }'
+assert 7 '#define F(x, ...) ret(x , ## __VA_ARGS__)
+int ret(int x) { return x; }
+int main() { return F(7); }'
+assert 5 '#define EMPTY
+#define ID(x) x
+int main() { EMPTY return ID(ID(5)); }'
+assert 2 '#define LEFT(x, y) x ## y
+int main() { return LEFT(, 2); }'
+assert 3 '#define F() 3
+int main() { return F(); }'
+
+assert 7 '#include "target/include/ret7.h"
+int main() { return ret7(); }'
+assert 42 '#include <nested.h>
+int main() { return NESTED; }' '-I target/include'
+assert 42 '#include "nested.h"
+int main() { return NESTED; }' '-Itarget/include'
+assert 7 '#define HEADER "target/include/ret7.h"
+#include HEADER
+int main() { return ret7(); }'
+assert 1 '#include "target/include/once.h"
+#include "target/include/once.h"
+int main() { return once(); }'
+assert 1 'int main() { return __has_include("target/include/ret7.h"); }'
+assert 0 'int main() { return __has_include("target/include/nope.h"); }'
+assert 1 'int main() { return __has_include(<nested.h>); }' '-I target/include'
+
+assert 1 '#if 1
+int main() { return 1; }
+#else
+int main() { return 2; }
+#endif'
+assert 2 '#if 0
+int main() { return 1; }
+#else
+int main() { return 2; }
+#endif'
+assert 6 '#if 0 && (1 / 0)
+#elif 1 || (1 / 0)
+int main() { return 6; }
+#endif'
+assert 7 '#if 1 ? 7 : 1 / 0
+int main() { return 7; }
+#endif'
+assert 8 '#if 0 ? 1 % 0 : 0 && 2 / 0
+#else
+int main() { return 8; }
+#endif'
+assert 3 "#if 0
+#error this platform isn't supported
+#endif
+int main() { return 3; }"
+assert 4 "#if 0
+\` @ \"abc '\\x'
+#else
+int main() { return 4; }
+#endif"
+assert 3 '#if 0
+#elif 1 + 1 == 3
+#else
+int main() { return 3; }
+#endif'
+assert 4 '#define A 2
+#if A == 1
+int main() { return 1; }
+#elif A == 2
+int main() { return 4; }
+#elif A == 2
+int main() { return 5; }
+#endif'
+assert 5 '#if 0
+#if 1
+#else
+#endif
+#elif 0
+#else
+int main() { return 5; }
+#endif'
+assert 6 '#define X
+#ifdef X
+int main() { return 6; }
+#endif
+#ifdef Y
+int main() { return 0; }
+#endif'
+assert 7 '#ifndef Y
+int main() { return 7; }
+#endif'
+assert 8 '#define A
+#if defined(A) && defined B == 0 && !defined(C)
+int main() { return 8; }
+#endif'
+assert 9 '#if UNDEFINED == 0 && (1 ? 2 : 3) == 2 && (-1 < 0u) == 0 && 7 % 4 == 3
+int main() { return 9; }
+#endif'
+assert 10 '#if (1 << 4) + (0x20 >> 1) - ~0 == 33 && (3 | 4) == 7 && (6 ^ 3) == 5
+int main() { return 10; }
+#endif'
+assert 11 '#define F(x) (x + 1)
+#if F(2) == 3 && __has_include("target/include/ret7.h") && defined __has_include
+int main() { return 11; }
+#endif'
+assert 12 '#include "target/include/guard.h"
+#include "target/include/guard.h"
+int main() { return guard(); }'
+
+assert 3 '
+
+int main() { return __LINE__; }'
+assert 3 '#define LINE __LINE__
+int main() {
+  return LINE; }'
+assert 7 '#define ADD(a, b) \
+  ((a) + \
+   (b))
+int main() { return ADD(3, 4); }'
+assert 5 'int main() { int fo\
+o = 5; return foo; }'
+assert 3 '#define X \
+  1
+int main() { return __LINE__; }'
+assert 1 '// a comment \
+return 2;
+int main() { return 1; }'
+assert 3 'int main() { return str_len("ab\
+c"); }'
+assert 7 'int main() { return str_len(__FILE__); }'
+assert 11 'int main() { return str_len(__DATE__); }'
+assert 8 'int main() { return str_len(__TIME__); }'
+assert 1 '#if __STDC__ && __STDC_VERSION__ >= 201112L && __x86_64__ && __linux__ && __LP64__ && __toycc__
+int main() { return 1; }
+#endif'
+assert 1 '#ifdef FOO
+int main() { return FOO; }
+#endif' '-DFOO'
+assert 42 'int main() { return FOO; }' '-DFOO=42'
+assert 5 'int main() { return ADD(2, 3); }' '-D ADD(a,b)=a+b'
+assert 2 '#ifndef FOO
+int main() { return 2; }
+#endif' '-DFOO -UFOO'
+assert 3 '#ifndef __toycc__
+int main() { return 3; }
+#endif' '-U __toycc__'
+assert 100 '#line 100
+int main() { return __LINE__; }'
+assert 21 '#line 20 "foo.c"
+int main() {
+  return __LINE__ + str_len(__FILE__) - 5; }'
+assert 5 '#define L 5
+#line L
+int main() { return __LINE__; }'
+assert 9 '# 9 "bar.c" 2
+int main() { return __LINE__; }'
+assert 41 '#include "target/include/lined.h"
+int main() { return INNER; }'
+assert 11 '#include "target/include/lined_once.h"
+#include "target/include/lined_once.h"
+int main() { return lined_once(); }'
+assert 6 '#line 50 "a.c"
+#line 3
+int main() { return __LINE__ + str_len(__FILE__); }'
+assert 4 '#pragma foo bar
+_Pragma("baz")
+#warning not an error
+int main() { return 4; }'
+assert 7 '_Pragma("once") int main() { return 7; }'
+
+assert_error 1 'int main() { return 1 }'
+assert_error 1 'int main() { 3 += 1; return 0; }'
+assert_error 1 'int main() { return 1 ? 2; }'
+assert_error 1 '#define ADD(a, b) a + b
+int main() { return ADD(1); }'
+assert_error 1 '#define F(x) x
+int main() { return F(1, 2); }'
+assert_error 1 '#define F(x) #y
+int main() { return 0; }'
+assert_error 1 '#define CAT(a, b) a ## b
+int main() { return CAT(+, /); }'
+assert_error 1 '#foo
+int main() { return 0; }'
+assert_error 2 '#define
+#define 3 4
+int main() { return 0; }'
+assert_error 1 '#include "target/include/nope.h"'
+assert_error 1 '#include "target/include/missing.h"'
+assert_error 1 '#include nope'
+assert_error 1 '#if 1
+int main() { return 0; }'
+assert_error 1 '#endif
+int main() { return 0; }'
+assert_error 1 '#if 1
+#else
+#else
+#endif
+int main() { return 0; }'
+assert 1 'int main() { int x=1; { int x=2; { int x=3; } } return x; }'
+assert 5 'int main() { int x=1; { x=5; int x=2; x=3; } return x; }'
+assert 2 'int main() { { int x=1; } { int x=2; return x; } }'
+assert 3 'int main() { int x=3; { int y=x; int x=y+1; } return x; }'
+assert 31 'int main() { int s=0; for (int i=0; i<5; i++) s+=i; for (int i=10; i<12; i++) s+=i; return s; }'
+assert 7 'int main() { int i=7; for (int i=0; i<3; i++) { int i=9; } return i; }'
+assert 6 'int main() { int s=0; for (int i=0, j=3; i<j; i++) s+=j; return s-3; }'
+assert 4 'int f(int x) { { int x=4; return x; } } int main() { return f(1); }'
+
+assert 0 'int x; int main() { return x; }'
+assert 3 'int counter; int inc() { counter += 1; return counter; } int main() { inc(); inc(); return inc(); }'
+assert 12 'int x = 3, y = 4 * 2 + 1; int main() { return x + y; }'
+assert 5 'int x = 5; int *p = &x; int main() { return *p; }'
+assert 1 'int *table = 0; int main() { return table == 0; }'
+assert 7 'int a; int b = 7; int *q = &b + 1 - 1; int main() { return *q + a; }'
+assert 4 'int x; int x = 4; int x; int main() { return x; }'
+assert 2 'int x = 1; int main() { int x = 2; return x; }'
+assert 11 'int x = 1 ? 10 : 20; int y = (1 << 4) % 5; int z = -1 < 0u; int main() { return x + y + z; }'
+assert 9 'int x; int y; int main() { x = 4; y = 5; return x + y; }'
+assert 3 'int *p; int main() { int x = 3; p = &x; return *p; }'
+assert 6 'int g = 6; int f() { return g; } int main() { int g = 1; return f(); }'
+
+assert 6 'int main() { int α=3; return α*2; }'
+assert 7 'int main() { int \u03b1=7; return α; }'
+assert 9 'int main() { int \U0001D465=4; int café=5; return 𝑥+caf\u00e9; }'
+assert 8 'int 二倍(int x) { return x*2; } int main() { return 二倍(4); }'
+assert 5 'int fα() { return 5; } int main() { return f\u03b1(); }'
+assert 6 'int main() { int ab\
+c\u00e9=6; return abcé; }'
+assert 4 '#define λ(x) (x+1)
+#define CAT(a, b) a##b
+int main() { int CAT(α, β)=3; return λ(αβ); }'
+assert 3 'int main() { int intα=3; return intα; }'
+
+assert 6 'int main() { int a[3]; a[0]=1; a[1]=2; a[2]=3; return a[0]+a[1]+a[2]; }'
+assert 15 'int main() { int a[2][3]; int i; int j; for (i=0;i<2;i++) for (j=0;j<3;j++) a[i][j]=i*3+j; return a[1][2]+a[0][1]*10; }'
+assert 9 'int main() { int a[3]; *a=4; *(a+1)=5; int *p=a; return p[0]+p[1]; }'
+assert 5 'int main() { int a[3]; 2[a]=5; return a[2]; }'
+assert 16 'int main() { int x=7; int a[4]; int y=9; a[0]=1; a[3]=2; return x+y; }'
+assert 2 'int main() { int a[3]; int *p=&a[2]; return p-a; }'
+assert 3 'int main() { int a[2][3]; return &a[1][0]-&a[0][0]; }'
+assert 1 'int main() { int a[2][3]; return &a[1]-&a[0]; }'
+assert 3 'int main() { int b[3]; return *(&b+1) - b; }'
+assert 2 'int main() { int a[2][3]; return *(&a+1) - a; }'
+assert 5 'int main() { int a[2][3]; a[1][2]=5; return (*(&a))[1][2]; }'
+assert 98 'int main() { return "abc"[1]; }'
+assert 6 'int sum(int a[3], int n) { int s=0; for (int i=0;i<n;i++) s+=a[i]; return s; } int main() { int b[3]; b[0]=1; b[1]=2; b[2]=3; return sum(b, 3); }'
+assert 12 'int f(int a, int b) { return a*10+b; } int main() { return f(1, 2); }'
+assert 16 'int g[2][2]; int *p = &g[1][1]; int *q = g[1] + 1; int main() { g[1][1]=8; return *p+*q; }'
+assert 2 'int g[3]; int *p = g + 2; int main() { return p - g; }'
+
+assert 44 'int main() { char c = 300; return c; }'
+assert 1 'int main() { char c = 255; return c == -1; }'
+assert 1 'int main() { short s = 65537; return s; }'
+assert 1 'int main() { short int s = -1; return s < 0; }'
+assert 1 'int main() { int x = 2147483647; x = x + 1; return x < 0; }'
+assert 1 'int main() { long x = 2147483647; x = x + 1; return x > 0; }'
+assert 1 'int main() { long long x = 4294967296; long int y = x; return y == 4294967296; }'
+assert 1 'int main() { return -1 == 4294967295u; }'
+assert 1 'int main() { return 4294967295u + 1 == 0; }'
+assert 1 'int main() { char a[2]; return &a[1] - &a[0]; }'
+assert 3 'int main() { char a[3]; short b[3]; int c[3]; return &a[2] - &a[0] + &c[1] - &c[0]; }'
+assert 72 'int main() { int a[2]; a[0]=1; a[1]=2; set_int(&a[0], 7); return a[0]*10 + a[1]; }'
+assert 6 'int main() { int a[3]; a[0]=1; a[1]=2; a[2]=3; return sum_ints(a, 3); }'
+assert 5 'int main() { short s[2]; s[1]=9; set_short(s, 5); return s[0] + s[1] - 9; }'
+assert 1 'int main() { return neg(3) < 0; }'
+assert 1 'int neg(int x); int main() { return neg(3) < 0; }'
+assert 3 'int main() { return add_long(1, 2); }'
+assert 3 'long add_long(long a, long b); int main() { return add_long(1, 2); }'
+assert 1 'long add_long(long a, long b); int main() { return add_long(4294967296, 1) == 4294967297; }'
+assert 1 'int main() { return add_long(4294967296, 1) == 1; }'
+assert 7 'int *id(int *p) { return p; } int main() { int x=7; return *id(&x); }'
+assert 1 'long f() { return 4294967296; } int main() { return f() == 4294967296; }'
+assert 1 'long f(); int main() { return f() == 4294967296; } long f() { return 4294967296; }'
+assert 3 'int f(); int f() { return 3; } int f(); int main() { return f(); }'
+assert 44 'char f() { return 300; } int main() { return f(); }'
+assert 8 'char f(char c) { return c; } int main() { return f(264); }'
+assert 97 'int main() { char *s = "abc"; return s[0]; }'
+assert 12 'char g1 = 1; short g2 = 2; int g3 = 3; long g4 = 6; int main() { return g1 + g2 + g3 + g4; }'
+assert 44 'char g = 300; int main() { return g; }'
+
+assert_preprocess 3 '#define NEG -x
+int main() { int x=3; return -NEG; }'
+assert_preprocess 12 '#define CAT(a, b) a##b
+#define STR(x) #x
+int main() { return CAT(1, 2) + str_len(STR(a)) - 1; }'
+assert_preprocess 49 '#include "target/include/ret7.h"
+#include "target/include/nested.h"
+int main() { return ret7() + NESTED; }'
+assert_preprocess 14 '#include "target/include/guard.h"
+int main() {
+
+
+
+  return guard() + __LINE__ - 4;
+}'
+
+if printf '#include "target/include/ret7.h"\nint x;\n' | ./target/debug/toycc -E - |
+    tr '\n' '|' | grep -qF '# 1 "<stdin>"|# 1 "target/include/ret7.h" 1|int ret7() { return 7; }|# 2 "<stdin>" 2|int x;|'; then
+  echo '-E linemarkers => OK'
+else
+  echo '-E linemarkers => unexpected output'
+  exit 1
+fi
+
+if printf '#warning careful\n#pragma foo\n#error stop\n' | ./target/debug/toycc - 2>&1 >/dev/null |
+    grep -c ': warning: ' | grep -qx 2; then
+  echo 'warnings => OK'
+else
+  echo 'warnings => not reported'
+  exit 1
+fi
+
+if printf '#define CAT(a, b) a##b\nint main() {\n  return CAT(x, y);\n}\n' | ./target/debug/toycc - 2>&1 >/dev/null |
+    grep -q '^<stdin>:3:10: error: ' &&
+  printf 'int main() {\n  return __LINE__ __LINE__;\n}\n' | ./target/debug/toycc - 2>&1 >/dev/null |
+    grep -q '^<stdin>:2:19: error: '; then
+  echo 'pasted token location => OK'
+else
+  echo 'pasted token location => reported in scratch space'
+  exit 1
+fi
+
+if printf 'int main() {\n  int x;\n  return x +;\n}\n' | ./target/debug/toycc - 2>&1 >/dev/null |
+    head -1 | grep -qx '<stdin>:3:13: error: expected an expression, found .;.'; then
+  echo 'error location => OK'
+else
+  echo 'error location => wrong line or column'
+  exit 1
+fi
+
+expected="<stdin>:1:31: error: invalid operands to binary '*'
+ 1 | int main() { int *p; return p * 2; }
+   |                               ^
+   |                             - left operand is a pointer
+   |                                 - right operand is an integer
+   = note: both operands must be integers"
+actual=$(echo 'int main() { int *p; return p * 2; }' | ./target/debug/toycc - 2>&1 >/dev/null)
+if [ "$actual" = "$expected" ]; then
+  echo 'diagnostic rendering => OK'
+else
+  echo 'diagnostic rendering => unexpected output:'
+  echo "$actual"
+  exit 1
+fi
+
+assert_error 1 'int main() { int \u0041=1; return 0; }'
+assert_error 1 'int main() { int \u0301x=1; return 0; }'
+assert_error 1 'int main() { int \u12=1; return 0; }'
+assert_error 1 'int main() { int \UFFFFFFFF=1; return 0; }'
+assert_error 1 'int main() { int a×=1; return 0; }'
+assert_error 1 'int main() { return 0; } @'
+assert_error 2 "int main() { return 'ab'; } \`"
+assert_error 1 "int main() { return 'é'; }"
+assert_error 1 "int main() { return '😀'; }"
+assert_error 1 "#if 'a
+#endif"
+assert_error 1 'int main() { return 0; } /* unterminated'
+assert_error 1 'int main() { return 0; } "abc'
+assert_error 1 "int main() { return '\\"
+assert_error 1 'int main() { return 0; } \U0001'
+assert_error 1 'int main() { return 0; } .'
+assert_error 1 'int main() { int x; int x; return 0; }'
+assert_error 1 'int f(int a, int a) { return 0; } int main() { return 0; }'
+assert_error 1 'int f(int a) { int a; return 0; } int main() { return 0; }'
+assert_error 1 'int main() { { int y=1; } return y; }'
+assert_error 1 'int main() { for (int i=0; i<3; i++) {} return i; }'
+assert_error 2 'int main() { int x, x; { int y; int y; } return 0; }'
+assert_error 1 'int x = 1; int x = 2; int main() { return 0; }'
+assert_error 1 'int *x; int x; int main() { return 0; }'
+assert_error 1 'int y; int x = y; int main() { return 0; }'
+assert_error 1 'int x = 1 / 0; int main() { return 0; }'
+assert_error 1 'int f(int a) { return a; } int g = a; int main() { return 0; }'
+assert_error 2 'int x = ; int y = &y + &y; int main() { return 0; }'
+assert_error 1 'int x; int x() { return 0; } int main() { return 0; }'
+assert_error 1 'int f() { return 0; } int f; int main() { return 0; }'
+assert_error 1 'int f() { return 1; } int f() { return 2; } int main() { return f(); }'
+assert_error 2 'int f() { return 1; } int f() { return 2; } int main() { return x; }'
+assert_error 1 'int x; int main() { return x(); }'
+assert_error 1 'int f() { return 2; } int main() { int f; return f(); }'
+assert_error 1 'int g; int x = &g; int main() { return 0; }'
+assert_error 1 'int main() { int *p; return p % 2; }'
+assert_error 1 'int main() { int *p; return ~p; }'
+assert_error 1 'int main() { int *p; return p << 1; }'
+assert_error 1 'int main() { int a[2]; return a << 1; }'
+assert_error 1 'int main() { int *p; int *q; return p & q; }'
+assert_error 1 'int main() { int x; int *p; return x | p; }'
+assert_error 1 'int main() { int *p; return p ^ 1; }'
+assert_error 1 'int main() { int *p; p %= 2; return 0; }'
+assert_error 1 'int main() { int *p; p >>= 1; return 0; }'
+assert_error 1 'int main() { int *p; return p * 2; }'
+assert_error 1 'int main() { int *p; return 4 / p; }'
+assert_error 1 'int main() { int *p; p *= 2; return 0; }'
+assert_error 1 'int main() { int *p; p /= 2; return 0; }'
+assert_error 1 'int main() { return *1; }'
+assert_error 1 'int main() { int x; return x[1]; }'
+assert_error 1 'char s[4]; long x = s + 1; int main() { return 0; }'
+assert_error 1 'int main() { int a[3]; int b[3]; a = b; return 0; }'
+assert_error 1 'int main() { int a[3]; a++; return 0; }'
+assert_error 1 'int main() { int a[3]; return (&a+1) - a; }'
+assert_error 1 'int main() { int a[4611686018427387904]; return 0; }'
+assert_error 1 'int a[1000][4611686018427387904]; int main() { return 0; }'
+assert_error 1 'int main() { char a[1073741824]; char b[1073741824]; char c[1073741824]; return 0; }'
+assert_error 1 'int main() { int a[3] = 1; return 0; }'
+assert_error 1 'int a[2] = 0; int main() { return 0; }'
+assert_error 1 'int main() { int a[x]; return 0; }'
+assert_error 1 '#error stop here
+int main() { return 0; }'
+assert_error 2 '#error one
+#error two
+int main() { return 0; }'
+assert_error 1 '#line 0
+int main() { return 0; }'
+assert_error 1 '#line 1 foo
+int main() { return 0; }'
+assert_error 1 '_Pragma(once)
+int main() { return 0; }'
+assert_error 1 '#if 1 && 1 / 0
+#endif'
+assert_error 1 '#if 1 / 0
+#endif
+int main() { return 0; }'
+assert_error 1 '#if
+#endif
+int main() { return 0; }'
+assert_error 1 '#if (1
+#endif
+int main() { return 0; }'
+assert_error 1 'int main() { return 10lu3; }'
+assert_error 1 'int main() { return 10lL; }'
+assert_error 1 'int main() { return 09; }'
+assert_error 1 'int main() { return 0x; }'
+assert_error 1 'int main() { return 0b12; }'
+assert_error 1 'int main() { return 18446744073709551616; }'
+assert_error 1 'int main() { return 1;'
+assert_error 1 'int f(int a, int b, int c, int d, int e, int g, int h) { return h; }'
+assert_error 1 'int main() { return sum_ints(1, 2, 3, 4, 5, 6, 7); }'
+assert_error 3 'int main() { int x=1; x=; return y; if (x x) return 1; return x; }'
+assert_error 2 'int main() { return 1 } int f(int 3) { return 0; } int g() { return 2; }'
+
 echo OK